@@ -0,0 +1,68 @@
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+
+// Samples as plain f64s; inputs[i] goes with targets[i].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Dataset {
+    pub inputs: Vec<Vec<f64>>,
+    pub targets: Vec<f64>,
+}
+
+impl Dataset {
+    pub fn new(inputs: Vec<Vec<f64>>, targets: Vec<f64>) -> Dataset {
+        assert_eq!(inputs.len(), targets.len(), "inputs and targets must have the same length");
+        Dataset { inputs, targets }
+    }
+
+    pub fn len(&self) -> usize {
+        self.targets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.targets.is_empty()
+    }
+}
+
+// Shuffles with a StdRng seeded from `seed`, so the same seed always gives the same split,
+// then puts round(len * test_ratio) samples into the test set and the rest into train.
+pub fn train_test_split(dataset: Dataset, test_ratio: f64, seed: u64) -> (Dataset, Dataset) {
+    assert!((0.0..=1.0).contains(&test_ratio), "test_ratio must be in [0, 1]");
+    let n_test = (dataset.len() as f64 * test_ratio).round() as usize;
+    let mut samples = dataset.inputs.into_iter().zip(dataset.targets).collect::<Vec<_>>();
+    samples.shuffle(&mut StdRng::seed_from_u64(seed));
+
+    let train = samples.split_off(n_test);
+    let (test_inputs, test_targets) = samples.into_iter().unzip();
+    let (train_inputs, train_targets) = train.into_iter().unzip();
+    (Dataset::new(train_inputs, train_targets), Dataset::new(test_inputs, test_targets))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::data::{train_test_split, Dataset};
+
+    fn dataset(n: usize) -> Dataset {
+        Dataset::new((0..n).map(|i| vec![i as f64, -(i as f64)]).collect(), (0..n).map(|i| i as f64).collect())
+    }
+
+    #[test]
+    fn test_train_test_split() {
+        let (train, test) = train_test_split(dataset(10), 0.2, 42);
+        assert_eq!((train.len(), test.len()), (8, 2));
+
+        let mut seen = train.targets.iter().chain(test.targets.iter()).copied().collect::<Vec<_>>();
+        seen.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(seen, (0..10).map(|i| i as f64).collect::<Vec<_>>());
+        for (x, y) in train.inputs.iter().zip(train.targets.iter()).chain(test.inputs.iter().zip(test.targets.iter())) {
+            assert_eq!(x[0], *y);
+        }
+
+        assert_eq!(train_test_split(dataset(10), 0.2, 42), (train, test));
+
+        let (train, test) = train_test_split(dataset(10), 0.0, 7);
+        assert_eq!((train.len(), test.len()), (10, 0));
+        let (train, test) = train_test_split(dataset(10), 1.0, 7);
+        assert_eq!((train.len(), test.len()), (0, 10));
+    }
+}
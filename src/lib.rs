@@ -1,7 +1,15 @@
-mod nn;
+pub mod data;
+pub mod metrics;
+pub mod nn;
+pub mod optim;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 
-use std::collections::{HashMap};
+use std::cell::{Cell, RefCell};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
 use std::fmt::{Debug, Display};
+use std::hash::{Hash, Hasher};
 use std::ops::{Deref, DerefMut};
 use std::rc::Rc;
 use crate::Op::{Binary, Unary};
@@ -15,11 +23,24 @@ pub enum BinaryOp {
     Pow,
 }
 
+// Custom ops compare their fn pointers by address, which is fine for structural equality:
+// at worst the same function compares unequal to itself across codegen units.
+#[allow(unpredictable_function_pointer_comparisons)]
 #[derive(Debug, Clone, PartialEq)]
 pub enum UnaryOp {
     Tanh,
+    TanhFast,
     Exp,
+    Ln,
     Relu,
+    Sigmoid,
+    Scale(f64),
+    Neg,
+    Sanitize,
+    // Identity in forward, scales the gradient by the factor in backward.
+    GradScale(f64),
+    // User-defined op, see Value::custom_unary.
+    Custom { name: String, forward: fn(f64) -> f64, backward: fn(f64, f64) -> f64 },
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -32,6 +53,64 @@ impl Op {
     pub fn is_binary(&self) -> bool {
         matches!(self, Op::Binary { .. } )
     }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Binary(_, _, op) => match op {
+                BinaryOp::Add => "Add",
+                BinaryOp::Sub => "Sub",
+                BinaryOp::Mul => "Mul",
+                BinaryOp::Div => "Div",
+                BinaryOp::Pow => "Pow",
+            },
+            Unary(_, op) => match op {
+                UnaryOp::Tanh => "Tanh",
+                UnaryOp::TanhFast => "TanhFast",
+                UnaryOp::Exp => "Exp",
+                UnaryOp::Ln => "Ln",
+                UnaryOp::Relu => "Relu",
+                UnaryOp::Sigmoid => "Sigmoid",
+                UnaryOp::Scale(_) => "Scale",
+                UnaryOp::Neg => "Neg",
+                UnaryOp::Sanitize => "Sanitize",
+                UnaryOp::GradScale(_) => "GradScale",
+                UnaryOp::Custom { .. } => "Custom",
+            },
+        }
+    }
+
+    // Evaluates the op on its current operands into a new node.
+    pub fn build(&self) -> Value {
+        match self {
+            Binary(lhs, rhs, op) => match op {
+                BinaryOp::Add => lhs.add(rhs),
+                BinaryOp::Sub => lhs.sub(rhs),
+                BinaryOp::Mul => lhs.mul(rhs),
+                BinaryOp::Div => lhs.div(rhs),
+                BinaryOp::Pow => lhs.pow(rhs),
+            },
+            Unary(x, op) => match op {
+                UnaryOp::Tanh => x.tanh(),
+                UnaryOp::TanhFast => x.tanh_fast(),
+                UnaryOp::Exp => x.exp(),
+                UnaryOp::Ln => x.ln(),
+                UnaryOp::Relu => x.relu(),
+                UnaryOp::Sigmoid => x.sigmoid(),
+                UnaryOp::Scale(k) => x.scale(*k),
+                UnaryOp::Neg => x.neg(),
+                UnaryOp::Sanitize => x.sanitize(),
+                UnaryOp::GradScale(k) => x.grad_scale(*k),
+                UnaryOp::Custom { name, forward, backward } => x.custom_unary(name, *forward, *backward),
+            },
+        }
+    }
+
+    pub fn map_operands(&self, mut f: impl FnMut(&Value) -> Value) -> Op {
+        match self {
+            Binary(lhs, rhs, op) => Binary(f(lhs), f(rhs), op.clone()),
+            Unary(x, op) => Unary(f(x), op.clone()),
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
@@ -45,12 +124,85 @@ impl ValueId {
     }
 }
 
+type GradHook = Rc<dyn Fn(f64) -> f64>;
+
+// Returned by Value::register_grad_hook; the hook stays registered until this is dropped.
+#[must_use = "the hook is removed as soon as the guard is dropped"]
+pub struct GradHookGuard {
+    id: ValueId,
+    hook: GradHook,
+}
+
+impl Drop for GradHookGuard {
+    fn drop(&mut self) {
+        // Leave a hook registered later for the same node alone. try_with because the
+        // guard may outlive the thread-local during thread teardown.
+        let _ = GRAD_HOOKS.try_with(|hooks| {
+            let mut hooks = hooks.borrow_mut();
+            if hooks.get(&self.id).is_some_and(|h| Rc::ptr_eq(h, &self.hook)) {
+                hooks.remove(&self.id);
+            }
+        });
+    }
+}
+
+thread_local! {
+    static GRAD_HOOKS: RefCell<HashMap<ValueId, GradHook>> = RefCell::new(HashMap::new());
+    static GRAD_ENABLED: Cell<bool> = const { Cell::new(true) };
+    // Nodes that keep recording ops inside no_grad, see Value::require_grad_here.
+    static GRAD_RETAINED: RefCell<HashSet<ValueId>> = RefCell::new(HashSet::new());
+}
+
+pub fn is_grad_enabled() -> bool {
+    GRAD_ENABLED.with(|enabled| enabled.get())
+}
+
+// Runs `f` with op recording disabled: every value created inside is a leaf, unless
+// one of its operands was marked with Value::require_grad_here. Marks made during the
+// call are dropped when it returns.
+pub fn no_grad<R>(f: impl FnOnce() -> R) -> R {
+    struct Restore(bool, HashSet<ValueId>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            GRAD_ENABLED.with(|enabled| enabled.set(self.0));
+            GRAD_RETAINED.with(|retained| *retained.borrow_mut() = std::mem::take(&mut self.1));
+        }
+    }
+
+    let _restore = Restore(
+        GRAD_ENABLED.with(|enabled| enabled.replace(false)),
+        GRAD_RETAINED.with(|retained| retained.borrow().clone()),
+    );
+    f()
+}
+
+// Scalar kernels shared by the Value methods and the batched activations in nn. Both stay
+// finite for any finite input: the (e^2x - 1)/(e^2x + 1) form gave inf/inf = NaN once
+// e^2x overflowed, and sigmoid only exponentiates non-positive numbers.
+fn tanh_data(x: f64) -> f64 {
+    x.tanh()
+}
+
+fn sigmoid_data(x: f64) -> f64 {
+    if x >= 0.0 {
+        1.0 / (1.0 + (-x).exp())
+    } else {
+        let e = x.exp();
+        e / (1.0 + e)
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct Value_ {
     pub data: f64,
     pub op: Option<Op>,
     pub label: String,
     pub id: ValueId,
+    // Set for parameters created with Value::new_param; constants and op results are false.
+    pub trainable: bool,
+    // Set by MLP::freeze_layer; optimizers leave frozen parameters untouched.
+    pub frozen: bool,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -70,6 +222,171 @@ impl GradStore {
     pub fn or_insert(&mut self, id: ValueId) -> &mut f64 {
         self.0.entry(id).or_insert(0.0)
     }
+
+    // Gradients of the trainable leaves reachable from `root`.
+    pub fn trainable_grads(&self, root: &Value) -> GradStore {
+        GradStore(
+            root.topo()
+                .iter()
+                .filter(|v| v.op.is_none() && v.trainable)
+                .filter_map(|v| self.0.get(&v.id).map(|g| (v.id, *g)))
+                .collect(),
+        )
+    }
+
+    // Sets each parameter's data to update(data, grad), with 0.0 for parameters that have
+    // no gradient. Like the optimizers, frozen parameters are left alone.
+    pub fn apply_to(&self, params: &mut [Value], update: impl Fn(f64, f64) -> f64) {
+        for p in params.iter_mut().filter(|p| !p.frozen) {
+            let g = self.0.get(&p.id).copied().unwrap_or(0.0);
+            p.data = update(p.data, g);
+        }
+    }
+
+    pub fn stats(&self, params: &[Value]) -> GradStats {
+        let grads = params.iter().map(|p| self.0.get(&p.id).copied().unwrap_or(0.0)).collect::<Vec<_>>();
+        if grads.is_empty() {
+            return GradStats::default();
+        }
+        let n = grads.len() as f64;
+        let mean = grads.iter().sum::<f64>() / n;
+        let var = grads.iter().map(|g| (g - mean).powi(2)).sum::<f64>() / n;
+        GradStats {
+            min: grads.iter().copied().fold(f64::INFINITY, f64::min),
+            max: grads.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+            mean,
+            std: var.sqrt(),
+            norm: grads.iter().map(|g| g * g).sum::<f64>().sqrt(),
+        }
+    }
+}
+
+// Summary of parameter gradients; parameters missing from the store count as 0.0.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct GradStats {
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub std: f64,
+    pub norm: f64,
+}
+
+// Work done by one backward pass: nodes processed per op kind (see Op::name) and in total,
+// leaves included.
+#[cfg(feature = "profile")]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BackwardProfile {
+    pub ops: HashMap<&'static str, usize>,
+    pub nodes: usize,
+}
+
+// A graph's topo order recorded by its leaves (by id), op kinds and operand positions.
+// Changing leaf data means running the forward pass again, which makes new intermediate
+// nodes, so those are matched by position only. A plan can be reused for any graph over
+// the same leaves with the same shape, and for nothing else.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BackwardPlan {
+    operands: Vec<Vec<usize>>,
+    fingerprint: u64,
+}
+
+impl BackwardPlan {
+    pub fn new(root: &Value) -> BackwardPlan {
+        BackwardPlan::from_topo(&root.topo())
+    }
+
+    fn from_topo(topo: &[Value]) -> BackwardPlan {
+        #[cfg(test)]
+        tests::PLANS_BUILT.with(|n| n.set(n.get() + 1));
+        let positions = topo.iter().enumerate().map(|(i, v)| (v.id, i)).collect::<HashMap<_, _>>();
+        let operands = topo
+            .iter()
+            .map(|v| v.operands().iter().map(|x| positions[&x.id]).collect())
+            .collect::<Vec<_>>();
+        let fingerprint = BackwardPlan::structure_hash(topo, &operands);
+        BackwardPlan { operands, fingerprint }
+    }
+
+    pub fn fingerprint(&self) -> u64 {
+        self.fingerprint
+    }
+
+    // Lays the nodes of root's graph out at the plan's positions. Returns None when the
+    // graph doesn't match the plan and the plan can't be reused.
+    fn bind(&self, root: &Value) -> Option<Vec<Value>> {
+        let mut slots: Vec<Option<Value>> = vec![None; self.operands.len()];
+        *slots.last_mut()? = Some(root.clone());
+        let mut placed = HashSet::from([root.id]);
+        for i in (0..slots.len()).rev() {
+            let v = slots[i].clone()?;
+            let operands = v.operands();
+            if operands.len() != self.operands[i].len() {
+                return None;
+            }
+            for (x, &p) in operands.iter().zip(&self.operands[i]) {
+                match &slots[p] {
+                    Some(existing) if !existing.same_node(x) => return None,
+                    Some(_) => {}
+                    // A node already sitting in another slot would be propagated twice.
+                    None if !placed.insert(x.id) => return None,
+                    None => slots[p] = Some((*x).clone()),
+                }
+            }
+        }
+        let topo = slots.into_iter().collect::<Option<Vec<_>>>()?;
+        (BackwardPlan::structure_hash(&topo, &self.operands) == self.fingerprint).then_some(topo)
+    }
+
+    fn structure_hash(topo: &[Value], operands: &[Vec<usize>]) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        for (v, ops) in topo.iter().zip(operands) {
+            match &v.op {
+                Some(op) => op.name().hash(&mut hasher),
+                None => v.id.hash(&mut hasher),
+            }
+            ops.hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum MathError {
+    // A negative base raised to a non-integer exponent has no real result.
+    NegativeBaseFractionalExponent { base: f64, exponent: f64 },
+}
+
+impl Display for MathError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MathError::NegativeBaseFractionalExponent { base, exponent } => {
+                write!(f, "cannot raise negative base {} to non-integer exponent {}", base, exponent)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MathError {}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RenderError {
+    Fmt(std::fmt::Error),
+}
+
+impl Display for RenderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RenderError::Fmt(e) => write!(f, "failed to write graph: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RenderError {}
+
+impl From<std::fmt::Error> for RenderError {
+    fn from(e: std::fmt::Error) -> Self {
+        RenderError::Fmt(e)
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -84,6 +401,8 @@ impl From<f64> for Value {
             op: None,
             label: "".to_string(),
             id: ValueId::new(),
+            trainable: false,
+            frozen: false,
         }))
     }
 }
@@ -115,12 +434,98 @@ impl Default for Value {
             op: None,
             label: "".to_string(),
             id: ValueId::new(),
+            trainable: false,
+            frozen: false,
         }))
     }
 }
 
+// Opt-in allocator for graph nodes. Nodes built through the pool are tracked, and
+// `recycle` takes back the ones nothing outside the pool references any more, so the next
+// rebuild reuses their allocations. Pool nodes always record their op, even in no_grad.
+#[derive(Debug, Default)]
+pub struct GraphPool {
+    free: Vec<Rc<Value_>>,
+    live: Vec<Value>,
+    allocations: usize,
+}
+
+impl GraphPool {
+    pub fn new() -> GraphPool {
+        GraphPool::default()
+    }
+
+    // Number of fresh node allocations made so far.
+    pub fn allocations(&self) -> usize {
+        self.allocations
+    }
+
+    pub fn leaf(&mut self, data: f64) -> Value {
+        self.alloc(data, None)
+    }
+
+    pub fn add(&mut self, lhs: &Value, rhs: &Value) -> Value {
+        self.alloc(lhs.data + rhs.data, Some(Op::Binary(lhs.clone(), rhs.clone(), BinaryOp::Add)))
+    }
+
+    pub fn mul(&mut self, lhs: &Value, rhs: &Value) -> Value {
+        self.alloc(lhs.data * rhs.data, Some(Op::Binary(lhs.clone(), rhs.clone(), BinaryOp::Mul)))
+    }
+
+    pub fn tanh(&mut self, x: &Value) -> Value {
+        self.alloc(x.data.tanh(), Some(Op::Unary(x.clone(), UnaryOp::Tanh)))
+    }
+
+    fn alloc(&mut self, data: f64, op: Option<Op>) -> Value {
+        let value = match self.free.pop() {
+            Some(mut rc) => {
+                let inner = Rc::get_mut(&mut rc).expect("free list nodes are uniquely owned");
+                inner.data = data;
+                inner.op = op;
+                inner.label.clear();
+                inner.id = ValueId::new();
+                inner.trainable = false;
+                inner.frozen = false;
+                Value(rc)
+            }
+            None => {
+                self.allocations += 1;
+                Value(Rc::new(Value_ {
+                    data,
+                    op,
+                    label: "".to_string(),
+                    id: ValueId::new(),
+                    trainable: false,
+                    frozen: false,
+                }))
+            }
+        };
+        self.live.push(value.clone());
+        value
+    }
+
+    pub fn recycle(&mut self) {
+        // Newest first: releasing a parent's op can leave its operands unreferenced too.
+        let mut kept = Vec::new();
+        for Value(mut rc) in std::mem::take(&mut self.live).into_iter().rev() {
+            match Rc::get_mut(&mut rc) {
+                Some(inner) => {
+                    inner.op = None;
+                    self.free.push(rc);
+                }
+                None => kept.push(Value(rc)),
+            }
+        }
+        kept.reverse();
+        self.live = kept;
+    }
+}
+
 macro_rules! bin_trait {
     ($trait:ident, $fn1:ident) => {
+        bin_trait!($trait, $fn1, |lhs: &Value, rhs: f64| Value::$fn1(lhs, &Value::from(rhs)));
+    };
+    ($trait:ident, $fn1:ident, $scalar:expr) => {
         impl<B: std::borrow::Borrow<Value>> std::ops::$trait<B> for Value {
             type Output = Value;
 
@@ -141,7 +546,7 @@ macro_rules! bin_trait {
             type Output = Value;
 
             fn $fn1(self, rhs: f64) -> Self::Output {
-                Value::$fn1(&self, &Value::from(rhs))
+                ($scalar)(&self, rhs)
             }
         }
 
@@ -149,7 +554,23 @@ macro_rules! bin_trait {
             type Output = Value;
 
             fn $fn1(self, rhs: f64) -> Self::Output {
-                Value::$fn1(&self, &Value::from(rhs))
+                ($scalar)(self, rhs)
+            }
+        }
+
+        impl std::ops::$trait<i32> for Value {
+            type Output = Value;
+
+            fn $fn1(self, rhs: i32) -> Self::Output {
+                ($scalar)(&self, rhs as f64)
+            }
+        }
+
+        impl std::ops::$trait<i32> for &Value {
+            type Output = Value;
+
+            fn $fn1(self, rhs: i32) -> Self::Output {
+                ($scalar)(self, rhs as f64)
             }
         }
     };
@@ -176,7 +597,8 @@ macro_rules! r_bin_trait {
 }
 
 bin_trait!(Add, add);
-bin_trait!(Mul, mul);
+// Multiplying by a scalar records a single Scale node instead of a constant leaf + Mul.
+bin_trait!(Mul, mul, Value::scale);
 bin_trait!(Sub, sub);
 bin_trait!(Div, div);
 
@@ -195,8 +617,14 @@ impl std::ops::Neg for Value {
 }
 
 impl Display for Value {
+    // `{:#}` prints a one-level summary of the node; operands are named by op kind only.
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Value[{}]", self.data)
+        if f.alternate() {
+            let op = self.op.as_ref().map_or("None", |op| op.name());
+            write!(f, "Value {{ id: {}, data: {}, label: {:?}, op: {} }}", self.id.0, self.data, self.label, op)
+        } else {
+            write!(f, "Value[{}]", self.data)
+        }
     }
 }
 
@@ -207,6 +635,8 @@ impl Value {
             op: None,
             label: label.to_string(),
             id: ValueId::new(),
+            trainable: false,
+            frozen: false,
         }))
     }
 
@@ -216,20 +646,114 @@ impl Value {
             op: None,
             label: "".to_string(),
             id: ValueId::new(),
+            trainable: false,
+            frozen: false,
         }))
     }
-    pub fn tanh(&self) -> Value {
-        let x = self.data;
-        let t = ((x * 2.0).exp() - 1.0) / ((x * 2.0).exp() + 1.0);
+
+    pub fn new_param(data: f64) -> Value {
         Value(Rc::new(Value_ {
-            data: t,
-            op: Some(Op::Unary(self.clone(), UnaryOp::Tanh)),
+            data,
+            op: None,
             label: "".to_string(),
             id: ValueId::new(),
+            trainable: true,
+            frozen: false,
         }))
     }
 
-    pub fn backward(&self) -> GradStore {
+    fn from_op(data: f64, op: Op) -> Value {
+        let enabled = is_grad_enabled();
+        let record = enabled || GRAD_RETAINED.with(|retained| {
+            let retained = retained.borrow();
+            match &op {
+                Binary(lhs, rhs, _) => retained.contains(&lhs.id) || retained.contains(&rhs.id),
+                Unary(x, _) => retained.contains(&x.id),
+            }
+        });
+        let value = Value(Rc::new(Value_ {
+            data,
+            op: record.then_some(op),
+            label: "".to_string(),
+            id: ValueId::new(),
+            trainable: false,
+            frozen: false,
+        }));
+        if record && !enabled {
+            value.require_grad_here();
+        }
+        value
+    }
+
+    // Inside no_grad, ops consuming this node (and their results, transitively) still
+    // record their op so gradients can flow back to it. The mark lasts until the
+    // innermost enclosing no_grad call returns. Outside no_grad every op is recorded
+    // anyway, so this does nothing.
+    pub fn require_grad_here(&self) {
+        if !is_grad_enabled() {
+            GRAD_RETAINED.with(|retained| retained.borrow_mut().insert(self.id));
+        }
+    }
+
+    // Unlike going through DerefMut, this never clones the node: it returns None when
+    // the value is shared.
+    pub fn data_mut(&mut self) -> Option<&mut f64> {
+        Rc::get_mut(&mut self.0).map(|inner| &mut inner.data)
+    }
+
+    pub fn detach(&self) -> Value {
+        Value::new_with_label(self.data, &self.label)
+    }
+
+    pub fn same_node(&self, other: &Value) -> bool {
+        self.id == other.id
+    }
+
+    pub fn same_value(&self, other: &Value) -> bool {
+        self.data == other.data
+    }
+
+    pub fn tanh(&self) -> Value {
+        Value::from_op(tanh_data(self.data), Op::Unary(self.clone(), UnaryOp::Tanh))
+    }
+
+    // Rational approximation x(27 + x²)/(27 + 9x²), clamped to ±1 beyond |x| = 3. It stays
+    // within 0.025 of tanh, and its backward is the exact derivative of the approximation,
+    // which is within 0.03 of the true tanh derivative.
+    pub fn tanh_fast(&self) -> Value {
+        let x = self.data;
+        let t = if x >= 3.0 {
+            1.0
+        } else if x <= -3.0 {
+            -1.0
+        } else {
+            x * (27.0 + x * x) / (27.0 + 9.0 * x * x)
+        };
+        Value::from_op(t, Op::Unary(self.clone(), UnaryOp::TanhFast))
+    }
+
+    pub fn sigmoid(&self) -> Value {
+        Value::from_op(sigmoid_data(self.data), Op::Unary(self.clone(), UnaryOp::Sigmoid))
+    }
+
+    // The hook sees this node's accumulated gradient and its result is what gets stored
+    // and propagated further. Hooks are per thread and are removed when the returned guard
+    // is dropped (or by remove_grad_hook); registering again replaces the node's hook.
+    pub fn register_grad_hook(&self, hook: impl Fn(f64) -> f64 + 'static) -> GradHookGuard {
+        let hook: GradHook = Rc::new(hook);
+        GRAD_HOOKS.with(|hooks| hooks.borrow_mut().insert(self.id, hook.clone()));
+        GradHookGuard { id: self.id, hook }
+    }
+
+    pub fn remove_grad_hook(&self) {
+        GRAD_HOOKS.with(|hooks| hooks.borrow_mut().remove(&self.id));
+    }
+
+    fn topo(&self) -> Vec<Value> {
+        Value::topo_from(std::slice::from_ref(self))
+    }
+
+    fn topo_from(roots: &[Value]) -> Vec<Value> {
         fn build_topo(value: &Value, visited: &mut HashMap<ValueId, bool>, topo: &mut Vec<Value>) {
             if visited.contains_key(&value.id) {
                 return;
@@ -248,124 +772,501 @@ impl Value {
             }
             topo.push(value.clone());
         }
-        let mut grad_store = GradStore::new();
-        grad_store.0.insert(self.id, 1.0);
         let mut topo = Vec::new();
         let mut visted = HashMap::new();
-        build_topo(self, &mut visted, &mut topo);
+        for root in roots {
+            build_topo(root, &mut visted, &mut topo);
+        }
+        topo
+    }
 
-        for v in topo.iter().rev() {
-            let v_grad = *grad_store.0.get(&v.id).unwrap();
+    // Nodes in topological order with their level: leaves are 0, every other node is one
+    // more than its deepest operand, so nodes sharing a level never depend on each other.
+    pub fn topo_levels(&self) -> Vec<(Value, usize)> {
+        let mut levels: HashMap<ValueId, usize> = HashMap::new();
+        self.topo()
+            .into_iter()
+            .map(|v| {
+                let level = match &v.op {
+                    Some(Binary(lhs, rhs, _)) => 1 + levels[&lhs.id].max(levels[&rhs.id]),
+                    Some(Unary(x, _)) => 1 + levels[&x.id],
+                    None => 0,
+                };
+                levels.insert(v.id, level);
+                (v, level)
+            })
+            .collect()
+    }
+
+    // Lays nodes out in columns by topo level, leaves on the left, with one rect per node
+    // and a line per operand edge.
+    pub fn to_svg(&self) -> Result<String, RenderError> {
+        use std::fmt::Write;
+
+        const NODE_W: usize = 120;
+        const NODE_H: usize = 36;
+        const GAP_X: usize = 60;
+        const GAP_Y: usize = 24;
+
+        fn escape(s: &str) -> String {
+            s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+        }
+
+        let levels = self.topo_levels();
+        let mut rows: Vec<usize> = Vec::new();
+        let mut pos: HashMap<ValueId, (usize, usize)> = HashMap::new();
+        for (v, level) in &levels {
+            if rows.len() <= *level {
+                rows.resize(level + 1, 0);
+            }
+            let x = GAP_X / 2 + level * (NODE_W + GAP_X);
+            let y = GAP_Y / 2 + rows[*level] * (NODE_H + GAP_Y);
+            rows[*level] += 1;
+            pos.insert(v.id, (x, y));
+        }
+        let width = rows.len() * (NODE_W + GAP_X);
+        let height = rows.iter().max().copied().unwrap_or(0) * (NODE_H + GAP_Y);
 
+        let mut svg = String::new();
+        writeln!(svg, r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}">"#, width, height)?;
+        for (v, _) in &levels {
+            let (x, y) = pos[&v.id];
+            let operands = match &v.op {
+                Some(Binary(lhs, rhs, _)) => vec![lhs, rhs],
+                Some(Unary(x, _)) => vec![x],
+                None => vec![],
+            };
+            for operand in operands {
+                let (ox, oy) = pos[&operand.id];
+                writeln!(svg, r#"<line x1="{}" y1="{}" x2="{}" y2="{}" stroke="black"/>"#,
+                         ox + NODE_W, oy + NODE_H / 2, x, y + NODE_H / 2)?;
+            }
+        }
+        for (v, _) in &levels {
+            let (x, y) = pos[&v.id];
+            let mut text = format!("{:.4}", v.data);
             if let Some(op) = &v.op {
-                match op {
-                    Binary(lhs, rhs, BinaryOp::Add) => {
-                        let g = grad_store.or_insert(lhs.id);
-                        *g += v_grad;
-                        let g = grad_store.or_insert(rhs.id);
-                        *g += v_grad;
-                    }
-                    Binary(lhs, rhs, BinaryOp::Mul) => {
-                        let g = grad_store.or_insert(lhs.id);
-                        *g += rhs.data * v_grad;
-                        let g = grad_store.or_insert(rhs.id);
-                        *g += lhs.data * v_grad;
-                    }
-                    Binary(_, _, BinaryOp::Div) => {
-                        unreachable!()
-                    }
-                    Binary(_, _, BinaryOp::Sub) => {
-                        unreachable!()
-                    }
-                    Binary(lhs, rhs, BinaryOp::Pow) => {
-                        let g = grad_store.or_insert(lhs.id);
-                        *g += rhs.data * lhs.data.powf(rhs.data - 1.0) * v_grad;
-                        let g = grad_store.or_insert(rhs.id);
-                        *g += lhs.data.powf(rhs.data) * v_grad;
-                    }
-                    Unary(x, UnaryOp::Tanh) => {
-                        let g = grad_store.or_insert(x.id);
-                        *g += (1.0 - v.data.powi(2)) * v_grad;
-                    }
-                    Unary(x, UnaryOp::Exp) => {
-                        let g = grad_store.or_insert(x.id);
-                        *g += v.data.exp() * v_grad;
-                    }
-                    Unary(x, UnaryOp::Relu) => {
-                        let g = grad_store.or_insert(x.id);
-                        *g += (x.data > 0.0) as i32 as f64 * v_grad;
+                text = format!("{} {}", op.name(), text);
+            }
+            if !v.label.is_empty() {
+                text = format!("{} | {}", v.label, text);
+            }
+            writeln!(svg, r#"<rect x="{}" y="{}" width="{}" height="{}" fill="white" stroke="black"/>"#, x, y, NODE_W, NODE_H)?;
+            writeln!(svg, r#"<text x="{}" y="{}" font-size="12" text-anchor="middle">{}</text>"#,
+                     x + NODE_W / 2, y + NODE_H / 2 + 4, escape(&text))?;
+        }
+        writeln!(svg, "</svg>")?;
+        Ok(svg)
+    }
+
+    // Rebuilds the graph bottom-up. Each op node is offered to `rewriter` with its operands
+    // already rewritten; returning Some replaces the node, None keeps it (rebuilt only if an
+    // operand changed). Leaves are kept as-is, so their gradients stay keyed the same.
+    pub fn map_graph(&self, rewriter: impl Fn(&Op) -> Option<Value>) -> Value {
+        let mut mapped: HashMap<ValueId, Value> = HashMap::new();
+        for v in self.topo() {
+            let new = match &v.op {
+                None => v.clone(),
+                Some(op) => {
+                    let mut changed = false;
+                    let new_op = op.map_operands(|x| {
+                        let new = mapped[&x.id].clone();
+                        changed |= !new.same_node(x);
+                        new
+                    });
+                    match rewriter(&new_op) {
+                        Some(replacement) => replacement,
+                        None if changed => {
+                            let mut rebuilt = new_op.build();
+                            rebuilt.label = v.label.clone();
+                            rebuilt
+                        }
+                        None => v.clone(),
                     }
                 }
+            };
+            mapped.insert(v.id, new);
+        }
+        mapped.remove(&self.id).unwrap()
+    }
+
+    // Collapses every op whose operands are all non-trainable leaves into a constant leaf,
+    // repeatedly up the graph. Note that inputs made with Value::new are non-trainable too.
+    pub fn fold_constants(&self) -> Value {
+        fn is_constant(v: &Value) -> bool {
+            v.op.is_none() && !v.trainable
+        }
+
+        self.map_graph(|op| {
+            let foldable = match op {
+                Binary(lhs, rhs, _) => is_constant(lhs) && is_constant(rhs),
+                Unary(x, _) => is_constant(x),
+            };
+            foldable.then(|| Value::from(op.build().data))
+        })
+    }
+
+    pub fn graph_depth(&self) -> usize {
+        self.topo_levels().last().map_or(0, |(_, level)| *level)
+    }
+
+    pub fn graph_size(&self) -> usize {
+        self.topo().len()
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn graph_to_json(&self) -> String {
+        #[derive(serde::Serialize)]
+        struct Node<'a> {
+            id: usize,
+            data: f64,
+            label: &'a str,
+            op: Option<&'static str>,
+        }
+
+        #[derive(serde::Serialize)]
+        struct Edge {
+            from: usize,
+            to: usize,
+        }
+
+        #[derive(serde::Serialize)]
+        struct Graph<'a> {
+            nodes: Vec<Node<'a>>,
+            edges: Vec<Edge>,
+        }
+
+        let topo = self.topo();
+        let mut graph = Graph { nodes: Vec::new(), edges: Vec::new() };
+        for v in &topo {
+            graph.nodes.push(Node {
+                id: v.id.0,
+                data: v.data,
+                label: &v.label,
+                op: v.op.as_ref().map(|op| op.name()),
+            });
+            match &v.op {
+                Some(Binary(lhs, rhs, _)) => {
+                    graph.edges.push(Edge { from: lhs.id.0, to: v.id.0 });
+                    graph.edges.push(Edge { from: rhs.id.0, to: v.id.0 });
+                }
+                Some(Unary(x, _)) => {
+                    graph.edges.push(Edge { from: x.id.0, to: v.id.0 });
+                }
+                None => {}
             }
         }
+        serde_json::to_string(&graph).expect("graph serialization cannot fail")
+    }
+
+    pub fn backward(&self) -> GradStore {
+        Value::backward_multi(std::slice::from_ref(self))
+    }
+
+    // Consumes the output so its references into the graph are released as soon as the
+    // gradients are computed; nodes nobody else holds are freed right away.
+    pub fn backward_and_drop(self) -> GradStore {
+        let grads = self.backward();
+        drop(self);
+        grads
+    }
+
+    // Seeds every root with 1.0, as if backward() were called on their sum.
+    pub fn backward_multi(roots: &[Value]) -> GradStore {
+        let mut grad_store = GradStore::new();
+        for root in roots {
+            *grad_store.or_insert(root.id) += 1.0;
+        }
+        Value::propagate(&Value::topo_from(roots), &mut grad_store, 0.0);
+        grad_store
+    }
+
+    // Like backward(), but only walks nodes that have one of `targets` in their subgraph,
+    // so gradients of everything else may be missing or partial.
+    pub fn backward_until(&self, targets: &[Value]) -> GradStore {
+        let targets = targets.iter().map(|t| t.id).collect::<HashSet<_>>();
+        self.backward_over(self.topo_reaching(|v| targets.contains(&v.id)))
+    }
+
+    // Topo order without the branches that contain no trainable leaf.
+    pub fn pruned_topo(&self) -> Vec<Value> {
+        self.topo_reaching(|v| v.op.is_none() && v.trainable)
+    }
+
+    // Backward over pruned_topo(): gradients of trainable leaves match backward(), while
+    // constant-only branches are skipped.
+    pub fn backward_trainable(&self) -> GradStore {
+        self.backward_over(self.pruned_topo())
+    }
+
+    // Nodes in topo order that are `is_target` or have a target in their subgraph.
+    fn topo_reaching(&self, is_target: impl Fn(&Value) -> bool) -> Vec<Value> {
+        let mut reaching: HashSet<ValueId> = HashSet::new();
+        self.topo()
+            .into_iter()
+            .filter(|v| {
+                let reaches = is_target(v) || match &v.op {
+                    Some(Binary(lhs, rhs, _)) => reaching.contains(&lhs.id) || reaching.contains(&rhs.id),
+                    Some(Unary(x, _)) => reaching.contains(&x.id),
+                    None => false,
+                };
+                if reaches {
+                    reaching.insert(v.id);
+                }
+                reaches
+            })
+            .collect()
+    }
+
+    #[cfg(feature = "profile")]
+    pub fn backward_with_profile(&self) -> (GradStore, BackwardProfile) {
+        let topo = self.topo();
+        let mut profile = BackwardProfile { nodes: topo.len(), ..Default::default() };
+        for op in topo.iter().filter_map(|v| v.op.as_ref()) {
+            *profile.ops.entry(op.name()).or_insert(0) += 1;
+        }
+        (self.backward_over(topo), profile)
+    }
+
+    pub fn structure_fingerprint(&self) -> u64 {
+        BackwardPlan::new(self).fingerprint
+    }
+
+    // Like backward(), but reuses the topo order in `plan` while the graph keeps the same
+    // structure; leaf data may differ. The plan is (re)built when missing or stale.
+    pub fn backward_cached(&self, plan: &mut Option<BackwardPlan>) -> GradStore {
+        let topo = match plan.as_ref().and_then(|p| p.bind(self)) {
+            Some(topo) => topo,
+            None => {
+                let topo = self.topo();
+                *plan = Some(BackwardPlan::from_topo(&topo));
+                topo
+            }
+        };
+        self.backward_over(topo)
+    }
+
+    fn operands(&self) -> Vec<&Value> {
+        match &self.op {
+            Some(Binary(lhs, rhs, _)) => vec![lhs, rhs],
+            Some(Unary(x, _)) => vec![x],
+            None => Vec::new(),
+        }
+    }
+
+    fn backward_over(&self, topo: Vec<Value>) -> GradStore {
+        let mut grad_store = GradStore::new();
+        grad_store.0.insert(self.id, 1.0);
+        Value::propagate(&topo, &mut grad_store, 0.0);
+        grad_store
+    }
+
+    // Approximate backward: edge contributions with magnitude below `threshold` are
+    // dropped, so nodes reached only through them get no gradient at all.
+    pub fn backward_approx(&self, threshold: f64) -> GradStore {
+        let mut grad_store = GradStore::new();
+        grad_store.0.insert(self.id, 1.0);
+        Value::propagate(&self.topo(), &mut grad_store, threshold);
         grad_store
     }
 
+    fn propagate(topo: &[Value], grad_store: &mut GradStore, threshold: f64) {
+        // Skips the per-node hook lookup in the common case of no hooks at all.
+        let has_hooks = GRAD_HOOKS.with(|hooks| !hooks.borrow().is_empty());
+        for v in topo.iter().rev() {
+            let Some(&grad) = grad_store.0.get(&v.id) else {
+                continue;
+            };
+            #[cfg(test)]
+            tests::PROPAGATED.with(|n| n.set(n.get() + 1));
+            let mut v_grad = grad;
+            let hook = if has_hooks { GRAD_HOOKS.with(|hooks| hooks.borrow().get(&v.id).cloned()) } else { None };
+            if let Some(hook) = hook {
+                v_grad = hook(v_grad);
+                grad_store.0.insert(v.id, v_grad);
+            }
+
+            v.chain(v_grad, |x, g| {
+                // With threshold 0.0 nothing is filtered, NaN contributions included.
+                if threshold <= 0.0 || g.abs() >= threshold {
+                    *grad_store.or_insert(x.id) += g;
+                }
+            });
+        }
+    }
+
+    // Runs a backward pass from self and reports each edge into `target` as
+    // (consumer node, contribution). The contributions sum to target's gradient.
+    pub fn trace_grad(&self, target: &Value) -> Vec<(Value, f64)> {
+        let grads = self.backward();
+        let mut contributions = Vec::new();
+        for v in self.topo() {
+            if let Some(&v_grad) = grads.0.get(&v.id) {
+                v.chain(v_grad, |x, g| {
+                    if x.same_node(target) {
+                        contributions.push((v.clone(), g));
+                    }
+                });
+            }
+        }
+        contributions
+    }
+
+    // Calls `accumulate(operand, contribution)` for each operand edge of this node, given
+    // the gradient flowing into it.
+    fn chain(&self, grad: f64, mut accumulate: impl FnMut(&Value, f64)) {
+        let Some(op) = &self.op else {
+            return;
+        };
+        match op {
+            Binary(lhs, rhs, BinaryOp::Add) => {
+                accumulate(lhs, grad);
+                accumulate(rhs, grad);
+            }
+            Binary(lhs, rhs, BinaryOp::Mul) => {
+                accumulate(lhs, rhs.data * grad);
+                accumulate(rhs, lhs.data * grad);
+            }
+            Binary(_, _, BinaryOp::Div) => {
+                unreachable!()
+            }
+            Binary(lhs, rhs, BinaryOp::Sub) => {
+                accumulate(lhs, grad);
+                accumulate(rhs, -grad);
+            }
+            Binary(lhs, rhs, BinaryOp::Pow) => {
+                accumulate(lhs, rhs.data * lhs.data.powf(rhs.data - 1.0) * grad);
+                // d(a^b)/db = a^b ln a only exists for a positive base; treat it as 0 otherwise.
+                let d_rhs = if lhs.data > 0.0 { self.data * lhs.data.ln() } else { 0.0 };
+                accumulate(rhs, d_rhs * grad);
+            }
+            Unary(x, UnaryOp::Tanh) => {
+                accumulate(x, (1.0 - self.data.powi(2)) * grad);
+            }
+            Unary(x, UnaryOp::TanhFast) => {
+                let t = x.data;
+                let d = if t.abs() >= 3.0 {
+                    0.0
+                } else {
+                    let den = 27.0 + 9.0 * t * t;
+                    ((27.0 + 3.0 * t * t) * den - 18.0 * t * t * (27.0 + t * t)) / (den * den)
+                };
+                accumulate(x, d * grad);
+            }
+            Unary(x, UnaryOp::Exp) => {
+                accumulate(x, self.data * grad);
+            }
+            Unary(x, UnaryOp::Ln) => {
+                accumulate(x, grad / x.data);
+            }
+            Unary(x, UnaryOp::Relu) => {
+                accumulate(x, (x.data > 0.0) as i32 as f64 * grad);
+            }
+            Unary(x, UnaryOp::Sigmoid) => {
+                accumulate(x, self.data * (1.0 - self.data) * grad);
+            }
+            Unary(x, UnaryOp::Scale(k)) => {
+                accumulate(x, k * grad);
+            }
+            Unary(x, UnaryOp::Neg) => {
+                accumulate(x, -grad);
+            }
+            Unary(x, UnaryOp::Sanitize) => {
+                accumulate(x, if x.data.is_finite() { grad } else { 0.0 });
+            }
+            Unary(x, UnaryOp::GradScale(k)) => {
+                accumulate(x, k * grad);
+            }
+            Unary(x, UnaryOp::Custom { backward, .. }) => {
+                accumulate(x, backward(x.data, self.data) * grad);
+            }
+        }
+    }
+
     pub fn add(&self, other: &Value) -> Value {
-        Value(Rc::new(Value_ {
-            data: self.data + other.data,
-            op: Some(Op::Binary(self.clone(), other.clone(), BinaryOp::Add)),
-            label: "".to_string(),
-            id: ValueId::new(),
-        }))
+        Value::from_op(self.data + other.data, Op::Binary(self.clone(), other.clone(), BinaryOp::Add))
     }
 
     pub fn mul(&self, other: &Value) -> Value {
-        Value(Rc::new(Value_ {
-            data: self.data * other.data,
-            op: Some(Op::Binary(self.clone(), other.clone(), BinaryOp::Mul)),
-            label: "".to_string(),
-            id: ValueId::new(),
-        }))
+        Value::from_op(self.data * other.data, Op::Binary(self.clone(), other.clone(), BinaryOp::Mul))
     }
 
     pub fn exp(&self) -> Value {
-        Value(Rc::new(Value_ {
-            data: self.data.exp(),
-            op: Some(Op::Unary(self.clone(), UnaryOp::Exp)),
-            label: "".to_string(),
-            id: ValueId::new(),
-        }))
+        Value::from_op(self.data.exp(), Op::Unary(self.clone(), UnaryOp::Exp))
+    }
+
+    pub fn ln(&self) -> Value {
+        Value::from_op(self.data.ln(), Op::Unary(self.clone(), UnaryOp::Ln))
     }
 
     pub fn pow(&self, other: &Value) -> Value {
-        Value(Rc::new(Value_ {
-            data: self.data.powf(other.data),
-            op: Some(Op::Binary(self.clone(), other.clone(), BinaryOp::Pow)),
-            label: "".to_string(),
-            id: ValueId::new(),
-        }))
+        Value::from_op(self.data.powf(other.data), Op::Binary(self.clone(), other.clone(), BinaryOp::Pow))
+    }
+
+    pub fn try_pow(&self, exp: &Value) -> Result<Value, MathError> {
+        if self.data < 0.0 && exp.data.fract() != 0.0 {
+            return Err(MathError::NegativeBaseFractionalExponent { base: self.data, exponent: exp.data });
+        }
+        Ok(self.pow(exp))
     }
 
     pub fn sqrt(&self) -> Value {
-        self.mul(&self)
+        self.pow(&Value::from(0.5))
     }
 
     pub fn div(&self, other: &Value) -> Value {
         self.mul(&other.pow(&Value::from(-1.0)))
     }
 
+    pub fn scale(&self, k: f64) -> Value {
+        Value::from_op(self.data * k, Op::Unary(self.clone(), UnaryOp::Scale(k)))
+    }
+
     pub fn neg(&self) -> Value {
-        self.mul(&Value::from(-1.0))
+        Value::from_op(-self.data, Op::Unary(self.clone(), UnaryOp::Neg))
+    }
+
+    // Unary op defined by the caller. `backward(input, output)` gets the operand's data and
+    // this node's data and returns the local derivative d(output)/d(input); backward() then
+    // multiplies it by the incoming gradient. `name` is only kept for debugging.
+    pub fn custom_unary(&self, name: &str, forward: fn(f64) -> f64, backward: fn(f64, f64) -> f64) -> Value {
+        let op = UnaryOp::Custom { name: name.to_string(), forward, backward };
+        Value::from_op(forward(self.data), Op::Unary(self.clone(), op))
+    }
+
+    // Same data as self; the gradient flowing back through it is multiplied by k.
+    pub fn grad_scale(&self, k: f64) -> Value {
+        Value::from_op(self.data, Op::Unary(self.clone(), UnaryOp::GradScale(k)))
+    }
+
+    // Identity for finite data; NaN and ±Inf become 0.0 and block the gradient.
+    pub fn sanitize(&self) -> Value {
+        let data = if self.data.is_finite() { self.data } else { 0.0 };
+        Value::from_op(data, Op::Unary(self.clone(), UnaryOp::Sanitize))
     }
 
     pub fn sub(&self, other: &Value) -> Value {
-        self.add(&other.neg())
+        Value::from_op(self.data - other.data, Op::Binary(self.clone(), other.clone(), BinaryOp::Sub))
     }
 
     pub fn relu(&self) -> Value {
-        Value(Rc::new(Value_ {
-            data: self.data.max(0.0),
-            op: None,
-            label: "ReLU".to_string(),
-            id: ValueId::new(),
-        }))
+        let mut out = Value::from_op(self.data.max(0.0), Op::Unary(self.clone(), UnaryOp::Relu));
+        out.label = "ReLU".to_string();
+        out
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+
+    thread_local! {
+        // Number of nodes processed by propagate() on this test thread.
+        pub(crate) static PROPAGATED: Cell<usize> = const { Cell::new(0) };
+        // Number of BackwardPlans built on this test thread.
+        pub(crate) static PLANS_BUILT: Cell<usize> = const { Cell::new(0) };
+    }
+
     #[test]
     fn test_tanh() {
         let x1 = Value::new_with_label(2.0, "x1");
@@ -375,7 +1276,7 @@ mod tests {
         let w2 = Value::new_with_label(1.0, "w2");
 
 
-        let b = Value::new_with_label(6.8813735870195432, "b");
+        let b = Value::new_with_label(6.881373587019543, "b");
 
         let mut x1w1 = x1 * w1;
         x1w1.label = "x1w1".to_string();
@@ -400,6 +1301,14 @@ mod tests {
         println!("{:?}", o);
     }
 
+    #[test]
+    fn test_sqrt() {
+        let x = Value::new(9.0);
+        let y = x.sqrt();
+        assert_eq!(y.data, 3.0);
+        assert!((y.backward().0[&x.id] - 0.5 / 3.0).abs() < 1e-12);
+    }
+
     #[test]
     fn test_backward() {
         let a = Value::new_with_label(3.0, "a");
@@ -407,4 +1316,628 @@ mod tests {
         let g = b.backward();
         assert_eq!(*(g.0.get(&a.id).unwrap()), 2.0);
     }
+
+    #[test]
+    fn test_grad_stats() {
+        let a = Value::new(1.0);
+        let b = Value::new(2.0);
+        let c = Value::new(-3.0);
+        // grads: a -> 2.0, b -> 4.0, c -> -6.0
+        let loss = (&a * 2.0) + (&b * &b) + (&c * &c);
+        let g = loss.backward();
+
+        let stats = g.stats(&[a, b, c]);
+        let mean = (2.0 + 4.0 - 6.0) / 3.0;
+        let var = ((2.0_f64 - mean).powi(2) + (4.0_f64 - mean).powi(2) + (-6.0_f64 - mean).powi(2)) / 3.0;
+        assert_eq!(stats.min, -6.0);
+        assert_eq!(stats.max, 4.0);
+        assert!((stats.mean - mean).abs() < 1e-12);
+        assert!((stats.std - var.sqrt()).abs() < 1e-12);
+        assert!((stats.norm - 56.0_f64.sqrt()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_same_node_and_value() {
+        let a = Value::new(2.0) * Value::new(3.0);
+        let cloned = a.clone();
+        assert!(a.same_node(&cloned));
+        assert!(a.same_value(&cloned));
+
+        let detached = a.detach();
+        assert!(detached.op.is_none());
+        assert!(!a.same_node(&detached));
+        assert!(a.same_value(&detached));
+
+        let other = Value::new(1.0);
+        assert!(!a.same_node(&other));
+        assert!(!a.same_value(&other));
+    }
+
+    #[test]
+    fn test_try_pow() {
+        let err = Value::new(-2.0).try_pow(&Value::new(0.5));
+        assert_eq!(err, Err(MathError::NegativeBaseFractionalExponent { base: -2.0, exponent: 0.5 }));
+
+        let ok = Value::new(4.0).try_pow(&Value::new(0.5)).unwrap();
+        assert_eq!(ok.data, 2.0);
+
+        let ok = Value::new(-2.0).try_pow(&Value::new(3.0)).unwrap();
+        assert_eq!(ok.data, -8.0);
+    }
+
+    #[test]
+    fn test_graph_size() {
+        let a = Value::new(2.0);
+        let b = Value::new(3.0);
+        let c = &a * &b;
+        // c is shared, so it only counts once
+        let d = (&c + &c).tanh();
+        assert_eq!(d.graph_size(), 5);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_graph_to_json() {
+        let a = Value::new_with_label(2.0, "a");
+        let b = Value::new_with_label(3.0, "b");
+        let c = &a * &b;
+        let d = (&c + &c).tanh();
+
+        let json: serde_json::Value = serde_json::from_str(&d.graph_to_json()).unwrap();
+        let nodes = json["nodes"].as_array().unwrap();
+        let edges = json["edges"].as_array().unwrap();
+        assert_eq!(nodes.len(), d.graph_size());
+        assert_eq!(edges.len(), 5);
+        assert!(nodes.iter().any(|n| n["label"] == "a" && n["op"].is_null()));
+        assert!(nodes.iter().any(|n| n["id"] == d.id.0 && n["op"] == "Tanh"));
+    }
+
+    #[test]
+    fn test_backward_multi() {
+        let shared = Value::new(2.0);
+        let x = Value::new(3.0);
+        let y = Value::new(5.0);
+        let r1 = &shared * &x;
+        let r2 = (&shared * &y).tanh();
+
+        let g = Value::backward_multi(&[r1.clone(), r2.clone()]);
+        let g1 = r1.backward();
+        let g2 = r2.backward();
+        assert_eq!(g.0[&shared.id], g1.0[&shared.id] + g2.0[&shared.id]);
+        assert_eq!(g.0[&x.id], 2.0);
+        assert_eq!(g.0[&y.id], g2.0[&y.id]);
+    }
+
+    #[test]
+    fn test_grad_hook() {
+        let a = Value::new(2.0);
+        let b = Value::new(3.0);
+        let c = &a * &b;
+        let d = (&c * 4.0).tanh();
+
+        let plain = d.backward();
+        let guard = c.register_grad_hook(|g| -g);
+        let hooked = d.backward();
+        drop(guard);
+
+        assert_eq!(hooked.0[&d.id], plain.0[&d.id]);
+        assert_eq!(hooked.0[&c.id], -plain.0[&c.id]);
+        assert_eq!(hooked.0[&a.id], -plain.0[&a.id]);
+        assert_eq!(hooked.0[&b.id], -plain.0[&b.id]);
+        assert_eq!(d.backward(), plain);
+        assert!(GRAD_HOOKS.with(|hooks| hooks.borrow().is_empty()));
+
+        // A stale guard doesn't remove a hook registered after it.
+        let old = c.register_grad_hook(|g| -g);
+        let _new = c.register_grad_hook(|g| 2.0 * g);
+        drop(old);
+        assert_eq!(d.backward().0[&c.id], 2.0 * plain.0[&c.id]);
+        c.remove_grad_hook();
+        assert_eq!(d.backward(), plain);
+    }
+
+    #[test]
+    fn test_topo_levels() {
+        let a = Value::new(1.0);
+        let b = Value::new(2.0);
+        let c = &a * &b;
+        let d = c.tanh();
+        let e = &d + &a;
+
+        let levels = e.topo_levels();
+        let level_of = |v: &Value| levels.iter().find(|(n, _)| n.same_node(v)).unwrap().1;
+        assert_eq!(levels.len(), 5);
+        assert_eq!(level_of(&a), 0);
+        assert_eq!(level_of(&b), 0);
+        assert_eq!(level_of(&c), 1);
+        assert_eq!(level_of(&d), 2);
+        assert_eq!(level_of(&e), 3);
+    }
+
+    #[test]
+    fn test_backward_until() {
+        let x = Value::new(0.5);
+        let w = Value::new(-1.5);
+        let mut noise = Value::new(1.0);
+        for i in 0..20 {
+            noise = (noise * (1.0 + i as f64 * 0.1)).tanh();
+        }
+        let out = (&x * &w).tanh() + noise;
+
+        PROPAGATED.with(|n| n.set(0));
+        let full = out.backward();
+        let full_work = PROPAGATED.with(|n| n.get());
+
+        PROPAGATED.with(|n| n.set(0));
+        let partial = out.backward_until(std::slice::from_ref(&x));
+        let partial_work = PROPAGATED.with(|n| n.get());
+
+        assert_eq!(partial.0[&x.id], full.0[&x.id]);
+        assert_eq!(full_work, out.graph_size());
+        assert_eq!(partial_work, 4);
+    }
+
+    #[test]
+    fn test_i32_ops() {
+        let x = Value::new(2.5);
+        assert_eq!((&x * 3).data, 7.5);
+        assert_eq!((&x - 1).data, 1.5);
+        assert_eq!((&x / 2).data, 1.25);
+        let y = x.clone() + 1;
+        assert_eq!(y.data, 3.5);
+        assert_eq!(y.backward().0[&x.id], 1.0);
+    }
+
+    #[test]
+    fn test_no_grad() {
+        let a = Value::new(2.0);
+        let b = Value::new(3.0);
+        let (c, d, e) = no_grad(|| {
+            assert!(!is_grad_enabled());
+            let unmarked = &a * &b;
+            assert!(unmarked.op.is_none());
+
+            a.require_grad_here();
+            let c = &a * &b;
+            let d = &b * &b;
+            let e = &c + &d;
+            (c, d, e)
+        });
+        assert!(is_grad_enabled());
+
+        assert!(c.op.is_some());
+        assert!(d.op.is_none());
+        assert!(e.op.is_some());
+        let g = e.backward();
+        assert_eq!(g.0[&a.id], 3.0);
+        assert_eq!(g.0[&b.id], 2.0);
+        assert_eq!(g.0[&d.id], 1.0);
+
+        // Marks don't outlive the scope they were made in.
+        assert!(GRAD_RETAINED.with(|retained| retained.borrow().is_empty()));
+        assert!(no_grad(|| &a * &b).op.is_none());
+
+        // Outside no_grad the mark is a no-op and doesn't leak into later scopes.
+        a.require_grad_here();
+        assert!(GRAD_RETAINED.with(|retained| retained.borrow().is_empty()));
+        assert!(no_grad(|| &a + &a).op.is_none());
+    }
+
+    #[test]
+    fn test_tanh_fast() {
+        for i in -600..=600 {
+            let x = Value::new(i as f64 / 100.0);
+            let exact = x.tanh();
+            let fast = x.tanh_fast();
+            assert!((fast.data - exact.data).abs() < 0.025);
+
+            let g_exact = exact.backward().0[&x.id];
+            let g_fast = fast.backward().0[&x.id];
+            assert!((g_fast - g_exact).abs() < 0.03);
+        }
+    }
+
+    #[test]
+    fn test_trace_grad() {
+        let a = Value::new(1.5);
+        let b = &a * 2.0;
+        let c = &a * 3.0;
+        let d = &b * &c;
+
+        let trace = d.trace_grad(&a);
+        assert_eq!(trace.len(), 2);
+        let from = |v: &Value| trace.iter().find(|(n, _)| n.same_node(v)).unwrap().1;
+        assert_eq!(from(&b), 2.0 * c.data);
+        assert_eq!(from(&c), 3.0 * b.data);
+        assert_eq!(trace.iter().map(|(_, g)| g).sum::<f64>(), d.backward().0[&a.id]);
+    }
+
+    #[test]
+    fn test_scale() {
+        let x = Value::new(3.0);
+        let constant = Value::from(-1.0);
+        assert_eq!(x.mul(&constant).graph_size(), 3);
+
+        // Plain leaves keep their gradient when multiplied by an op node.
+        let (a, b) = (Value::new(6.0), Value::new(3.0));
+        let g = (&a / &b).backward();
+        assert!((g.0[&a.id] - 1.0 / 3.0).abs() < 1e-12);
+        assert!((g.0[&b.id] + 6.0 / 9.0).abs() < 1e-12);
+        let x2 = Value::new(2.0);
+        assert_eq!(x2.mul(&(&x2 + 1.0)).backward().0[&x2.id], 5.0);
+
+        let y = x.neg();
+        assert_eq!(y.data, -3.0);
+        assert_eq!(y.graph_size(), 2);
+        let g = y.backward();
+        assert_eq!(g.0.len(), 2);
+        assert_eq!(g.0[&x.id], -1.0);
+
+        let z = 2.0 * &x * 4;
+        assert_eq!(z.data, 24.0);
+        assert_eq!(z.graph_size(), 3);
+        assert_eq!(z.backward().0[&x.id], 8.0);
+    }
+
+    #[test]
+    fn test_to_svg() {
+        let a = Value::new_with_label(2.0, "a<b");
+        let b = Value::new(3.0);
+        let c = &a * &b;
+        let d = (&c + &a).tanh();
+
+        let svg = d.to_svg().unwrap();
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.trim_end().ends_with("</svg>"));
+        assert_eq!(svg.matches("<rect").count(), d.graph_size());
+        assert_eq!(svg.matches("<line").count(), 5);
+        assert_eq!(svg.matches("<text").count(), svg.matches("</text>").count());
+        assert!(svg.contains("a&lt;b"));
+        for line in svg.lines().filter(|l| l.starts_with("<rect") || l.starts_with("<line")) {
+            assert!(line.ends_with("/>"));
+        }
+    }
+
+    #[test]
+    fn test_backward_and_drop() {
+        let x = Value::new(2.0);
+        let y = (&x * &x).tanh();
+        assert_eq!(Rc::strong_count(&x.0), 3);
+
+        let expected = y.backward();
+        let g = y.backward_and_drop();
+        assert_eq!(g, expected);
+        assert_eq!(Rc::strong_count(&x.0), 1);
+    }
+
+    #[test]
+    fn test_graph_pool() {
+        let mut pool = GraphPool::new();
+        let mut first_allocations = 0;
+        for step in 0..10 {
+            let w = [pool.leaf(0.5), pool.leaf(-1.5)];
+            let x = [pool.leaf(2.0), pool.leaf(1.0)];
+            let b = pool.leaf(0.1);
+            let mut act = b.clone();
+            for (w, x) in w.iter().zip(x.iter()) {
+                let wx = pool.mul(w, x);
+                act = pool.add(&act, &wx);
+            }
+            let out = pool.tanh(&act);
+
+            let g = out.backward();
+            assert!((out.data - (-0.4_f64).tanh()).abs() < 1e-12);
+            assert!((g.0[&w[0].id] - 2.0 * (1.0 - out.data.powi(2))).abs() < 1e-12);
+
+            drop((w, x, b, act, out));
+            pool.recycle();
+            if step == 0 {
+                first_allocations = pool.allocations();
+            }
+        }
+        assert_eq!(first_allocations, 10);
+        assert_eq!(pool.allocations(), first_allocations);
+    }
+
+    #[test]
+    fn test_trainable_grads() {
+        let w = Value::new_param(0.5);
+        let lr = Value::from(0.1);
+        let x = Value::new(2.0);
+        assert!(w.trainable);
+        assert!(!lr.trainable && !x.trainable);
+
+        let out = (&w * &x) * &lr;
+        assert!(!out.trainable);
+        let grads = out.backward();
+        let trainable = grads.trainable_grads(&out);
+        assert_eq!(trainable.0.len(), 1);
+        assert_eq!(trainable.0[&w.id], grads.0[&w.id]);
+        assert!(!trainable.0.contains_key(&lr.id));
+        assert!(!trainable.0.contains_key(&x.id));
+    }
+
+    #[test]
+    fn test_map_graph() {
+        let x = Value::new_param(0.8);
+        let one = Value::from(1.0);
+        let zero = Value::from(0.0);
+        let out = (x.mul(&one).add(&zero).tanh() * &x).exp();
+
+        let simplified = out.map_graph(|op| match op {
+            Binary(lhs, rhs, BinaryOp::Mul) if rhs.op.is_none() && rhs.data == 1.0 => Some(lhs.clone()),
+            Binary(lhs, rhs, BinaryOp::Add) if rhs.op.is_none() && rhs.data == 0.0 => Some(lhs.clone()),
+            _ => None,
+        });
+        assert_eq!(out.graph_size(), 8);
+        assert_eq!(simplified.graph_size(), 4);
+        assert_eq!(simplified.data, out.data);
+        assert!((simplified.backward().0[&x.id] - out.backward().0[&x.id]).abs() < 1e-12);
+
+        let untouched = out.map_graph(|_| None);
+        assert!(untouched.same_node(&out));
+    }
+
+    #[test]
+    fn test_fold_constants() {
+        let x = Value::new_param(1.5);
+        let out = (Value::from(2.0) + 3.0) * &x;
+        let folded = out.fold_constants();
+
+        assert_eq!(out.graph_size(), 5);
+        assert_eq!(folded.graph_size(), 3);
+        match &folded.op {
+            Some(Binary(c, rhs, BinaryOp::Mul)) => {
+                assert!(c.op.is_none() && c.data == 5.0);
+                assert!(rhs.same_node(&x));
+            }
+            op => panic!("unexpected op {:?}", op),
+        }
+        assert_eq!(folded.data, out.data);
+        assert_eq!(folded.backward().0[&x.id], out.backward().0[&x.id]);
+
+        let nested = ((Value::from(2.0) * 3.0).exp() + &x).tanh().fold_constants();
+        assert_eq!(nested.graph_size(), 4);
+    }
+
+    #[test]
+    fn test_data_mut() {
+        let mut x = Value::new_param(1.0);
+        let before = Rc::as_ptr(&x.0);
+        *x.data_mut().unwrap() += 0.5;
+        assert_eq!(x.data, 1.5);
+        assert_eq!(Rc::as_ptr(&x.0), before);
+
+        let shared = x.clone();
+        assert!(x.data_mut().is_none());
+        drop(shared);
+        assert!(x.data_mut().is_some());
+    }
+
+    #[test]
+    fn test_sub_neg_ops() {
+        let a = Value::new(5.0);
+        let b = Value::new(3.0);
+        let c = &a - &b;
+        assert_eq!(c.data, 2.0);
+        assert_eq!(c.graph_size(), 3);
+        assert_eq!(c.op.as_ref().map(|op| op.name()), Some("Sub"));
+        let g = c.backward();
+        assert_eq!(g.0[&a.id], 1.0);
+        assert_eq!(g.0[&b.id], -1.0);
+
+        let n = -(&a * &b);
+        assert_eq!(n.data, -15.0);
+        assert_eq!(n.op.as_ref().map(|op| op.name()), Some("Neg"));
+        let g = n.backward();
+        assert_eq!(g.0[&a.id], -3.0);
+        assert_eq!(g.0[&b.id], -5.0);
+    }
+
+    #[test]
+    fn test_pruned_topo() {
+        let w = Value::new_param(0.5);
+        let x = Value::new(2.0);
+        let mut constant = Value::from(1.0);
+        for i in 0..10 {
+            constant = (constant * (1.0 + i as f64)).tanh();
+        }
+        let out = (&w * &x).tanh() + &constant;
+
+        let pruned = out.pruned_topo();
+        assert_eq!(pruned.len(), 4);
+        assert!(pruned.iter().all(|v| !v.same_node(&constant) && !v.same_node(&x)));
+
+        let full = out.backward();
+        PROPAGATED.with(|n| n.set(0));
+        let g = out.backward_trainable();
+        assert_eq!(PROPAGATED.with(|n| n.get()), 4);
+        assert_eq!(g.0[&w.id], full.0[&w.id]);
+    }
+
+    #[test]
+    fn test_alternate_display() {
+        let a = Value::new_with_label(2.0, "a");
+        let b = Value::new_with_label(-3.0, "b");
+        let c = &a * &b;
+
+        assert_eq!(format!("{}", c), "Value[-6]");
+        let s = format!("{:#}", c);
+        assert_eq!(s, format!("Value {{ id: {}, data: -6, label: \"\", op: Mul }}", c.id.0));
+        assert!(!s.contains("-3") && !s.contains("\"b\""));
+        assert_eq!(format!("{:#}", a), format!("Value {{ id: {}, data: 2, label: \"a\", op: None }}", a.id.0));
+    }
+
+    #[test]
+    fn test_sanitize() {
+        let x = Value::new(f64::INFINITY);
+        let y = x.sanitize();
+        assert_eq!(y.data, 0.0);
+        assert_eq!(y.backward().0[&x.id], 0.0);
+
+        let n = Value::new(f64::NAN);
+        assert_eq!(n.sanitize().data, 0.0);
+
+        let a = Value::new(1.5);
+        let out = a.sanitize() * 2.0;
+        assert_eq!(out.data, 3.0);
+        assert_eq!(out.backward().0[&a.id], 2.0);
+    }
+
+    #[test]
+    fn test_backward_cached() {
+        let mut w = Value::new_param(0.5);
+        let b = Value::new_param(-1.0);
+        let x = Value::new(1.5);
+        let forward = |w: &Value, x: &Value| (w * x + &b).tanh() * w;
+
+        // New leaf data means a new forward pass over the same leaves: the plan is reused.
+        let mut plan = None;
+        for step in 0..3 {
+            let out = forward(&w, &x);
+            PLANS_BUILT.with(|n| n.set(0));
+            let g = out.backward_cached(&mut plan);
+            assert_eq!(PLANS_BUILT.with(|n| n.get()), if step == 0 { 1 } else { 0 });
+            assert_eq!(g, out.backward());
+            assert_eq!(plan.as_ref().unwrap().fingerprint(), out.structure_fingerprint());
+            w.data += 0.25;
+        }
+
+        // The same shape over a different leaf is a different graph.
+        let rebuilt = forward(&w, &Value::new(1.5));
+        assert_ne!(rebuilt.structure_fingerprint(), forward(&w, &x).structure_fingerprint());
+        PLANS_BUILT.with(|n| n.set(0));
+        assert_eq!(rebuilt.backward_cached(&mut plan), rebuilt.backward());
+        assert_eq!(PLANS_BUILT.with(|n| n.get()), 1);
+
+        let other = (&w * &x + &b).sigmoid() * &w;
+        PLANS_BUILT.with(|n| n.set(0));
+        let g = other.backward_cached(&mut plan);
+        assert_eq!(PLANS_BUILT.with(|n| n.get()), 1);
+        assert_eq!(g, other.backward());
+    }
+
+    #[test]
+    fn test_backward_cached_shared_node() {
+        // Two tanh nodes over c, then a single one used twice: same leaves, kinds and
+        // positions, but binding would put t in two slots.
+        let c = Value::new(0.7);
+        let mut plan = Some(BackwardPlan::new(&(c.tanh() * c.tanh())));
+        let t = c.tanh();
+        let out = &t * &t;
+
+        PLANS_BUILT.with(|n| n.set(0));
+        let g = out.backward_cached(&mut plan);
+        assert_eq!(PLANS_BUILT.with(|n| n.get()), 1);
+        assert_eq!(g, out.backward());
+        assert!((g.0[&c.id] - 2.0 * t.data * (1.0 - t.data * t.data)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_backward_approx() {
+        let a = Value::new(2.0);
+        let b = Value::new(3.0);
+        let c = Value::new(4.0);
+        let out = &a * 1e-4 * &b + &c * 5.0;
+
+        assert_eq!(out.backward_approx(0.0), out.backward());
+
+        let x = Value::new(0.0);
+        let nan = x.ln() * Value::new(0.0);
+        assert!(nan.backward().0[&x.id].is_nan());
+        assert!(nan.backward_approx(0.0).0[&x.id].is_nan());
+
+        let g = out.backward_approx(1e-3);
+        assert_eq!(g.0[&c.id], 5.0);
+        assert!(!g.0.contains_key(&a.id));
+        assert!(!g.0.contains_key(&b.id));
+    }
+
+    #[test]
+    fn test_pow_learned_exponent() {
+        let a = Value::new(2.0);
+        let b = Value::new(3.0);
+        let g = a.pow(&b).backward();
+        assert!((g.0[&a.id] - 3.0 * 4.0).abs() < 1e-12);
+        assert!((g.0[&b.id] - 8.0 * 2.0_f64.ln()).abs() < 1e-12);
+        assert_eq!(Value::new(-2.0).pow(&b).backward().0[&b.id], 0.0);
+
+        let mut a = Value::new_param(2.5);
+        let mut b = Value::new_param(1.5);
+        let mut loss = f64::INFINITY;
+        for _ in 0..500 {
+            let l = (a.pow(&b) - 8.0).pow(&Value::from(2.0));
+            let g = l.backward();
+            let (ga, gb) = (g.0[&a.id], g.0[&b.id]);
+            assert!(ga.is_finite() && gb.is_finite());
+            a.data -= 0.005 * ga;
+            b.data -= 0.005 * gb;
+            loss = l.data;
+        }
+        assert!(loss < 1e-8);
+        assert!(a.data > 0.0 && (a.data.powf(b.data) - 8.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_custom_unary() {
+        let x = Value::new(3.0);
+        let y = x.custom_unary("square", |x| x * x, |x, _| 2.0 * x);
+        assert_eq!(y.data, 9.0);
+        assert!(matches!(&y.op, Some(Unary(_, UnaryOp::Custom { name, .. })) if name == "square"));
+
+        let out = &y * 0.5 + y.custom_unary("half", |y| y / 2.0, |_, _| 0.5);
+        assert_eq!(out.data, 9.0);
+        assert_eq!(out.backward().0[&x.id], 2.0 * 3.0);
+    }
+
+    #[test]
+    fn test_grad_store_apply_to() {
+        let w = Value::new_param(1.0);
+        let b = Value::new_param(-0.5);
+        let unused = Value::new_param(2.0);
+        let x = Value::new(3.0);
+        let mut frozen = Value::new_param(4.0);
+        frozen.frozen = true;
+        let grads = (&w * &x + &b + &frozen).backward();
+
+        let mut params = [w, b, unused, x, frozen];
+        grads.apply_to(&mut params, |data, grad| data - 0.1 * grad);
+        assert!((params[0].data - (1.0 - 0.1 * 3.0)).abs() < 1e-12);
+        assert!((params[1].data - (-0.5 - 0.1)).abs() < 1e-12);
+        assert_eq!(params[2].data, 2.0);
+        // Plain leaves passed in are updated too; only frozen ones are skipped.
+        assert!((params[3].data - (3.0 - 0.1)).abs() < 1e-12);
+        assert_eq!(params[4].data, 4.0);
+    }
+
+    #[cfg(feature = "profile")]
+    #[test]
+    fn test_backward_with_profile() {
+        let n = crate::nn::MLP::new(3, &[4, 4, 1]);
+        let x = [Value::new(2.0), Value::new(3.0), Value::new(-1.0)];
+        let out = &n.forward(&x)[0];
+
+        let (grads, profile) = out.backward_with_profile();
+        assert_eq!(grads, out.backward());
+        // One Mul and one Add per weight, one Tanh per neuron.
+        assert_eq!(profile.ops["Mul"], 3 * 4 + 4 * 4 + 4);
+        assert_eq!(profile.ops["Add"], 3 * 4 + 4 * 4 + 4);
+        assert_eq!(profile.ops["Tanh"], 4 + 4 + 1);
+        assert_eq!(profile.ops.len(), 3);
+        assert_eq!(profile.nodes, 32 + 32 + 9 + x.len() + n.parameters().len());
+    }
+
+    #[test]
+    fn test_stable_activations() {
+        for x in [-1e6, -800.0, -20.0, 0.0, 20.0, 800.0, 1e6] {
+            let v = Value::new(x);
+            for out in [v.tanh(), v.sigmoid()] {
+                assert!(out.data.is_finite());
+                assert!(out.backward().0[&v.id].is_finite());
+            }
+        }
+        assert_eq!(Value::new(800.0).tanh().data, 1.0);
+        assert_eq!(Value::new(-800.0).sigmoid().data, 0.0);
+        assert!((Value::new(-2.0).sigmoid().data - 1.0 / (1.0 + 2.0_f64.exp())).abs() < 1e-15);
+    }
 }
@@ -0,0 +1,36 @@
+use crate::Value;
+
+// Coefficient of determination 1 - SS_res / SS_tot over the values' data. When the targets
+// are constant SS_tot is zero; that case gives 1.0 for a perfect fit and 0.0 otherwise.
+pub fn r2_score(predictions: &[Value], targets: &[Value]) -> f64 {
+    assert_eq!(predictions.len(), targets.len(), "predictions and targets must have the same length");
+    if targets.is_empty() {
+        return 0.0;
+    }
+    let mean = targets.iter().map(|t| t.data).sum::<f64>() / targets.len() as f64;
+    let ss_res = predictions.iter().zip(targets.iter()).map(|(p, t)| (t.data - p.data).powi(2)).sum::<f64>();
+    let ss_tot = targets.iter().map(|t| (t.data - mean).powi(2)).sum::<f64>();
+    if ss_tot == 0.0 {
+        return if ss_res == 0.0 { 1.0 } else { 0.0 };
+    }
+    1.0 - ss_res / ss_tot
+}
+
+#[cfg(test)]
+mod test {
+    use crate::metrics::r2_score;
+    use crate::Value;
+
+    #[test]
+    fn test_r2_score() {
+        let targets = [1.0, 2.0, 4.0, 7.0].map(Value::new);
+        assert_eq!(r2_score(&targets, &targets), 1.0);
+
+        let mean = [3.5; 4].map(Value::new);
+        assert_eq!(r2_score(&mean, &targets), 0.0);
+
+        let off = [1.5, 2.0, 4.0, 6.5].map(Value::new);
+        let expected = 1.0 - 0.5 / 21.0;
+        assert!((r2_score(&off, &targets) - expected).abs() < 1e-12);
+    }
+}
@@ -1,5 +1,9 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
 use rand::Rng;
-use crate::Value;
+use crate::optim::Optimizer;
+use crate::{no_grad, GradStore, Op, UnaryOp, Value};
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Neuron {
@@ -11,8 +15,8 @@ pub struct Neuron {
 impl Neuron {
     pub fn new(nin: usize, non_lin: bool) -> Neuron {
         Neuron {
-            weights: (0..nin).map(|_| Value::new(rand::thread_rng().gen_range(-1.0..=1.0))).collect::<Vec<_>>(),
-            bias: Value::default(),
+            weights: (0..nin).map(|_| Value::new_param(rand::thread_rng().gen_range(-1.0..=1.0))).collect::<Vec<_>>(),
+            bias: Value::new_param(0.0),
             non_lin,
         }
     }
@@ -23,11 +27,17 @@ impl Neuron {
     }
 
     pub fn forward(&self, inputs: &[Value]) -> Value {
+        self.forward_detailed(inputs).1
+    }
+
+    // Returns (pre_activation, activation); they are the same node for linear neurons.
+    pub fn forward_detailed(&self, inputs: &[Value]) -> (Value, Value) {
         let act = self.weights.iter().zip(inputs.iter()).fold(self.bias.clone(), |acc, (w, i)| acc + w * i);
         if self.non_lin {
-            act.tanh()
+            let out = act.tanh();
+            (act, out)
         } else {
-            act
+            (act.clone(), act)
         }
     }
 
@@ -52,6 +62,21 @@ impl Layer {
         }
     }
 
+    // Decoder layer whose weights are the transpose of `other`'s, sharing the same nodes so
+    // gradients from both uses accumulate into them. Biases are fresh.
+    pub fn tied_from(other: &Layer) -> Layer {
+        let nin = other.neurons.first().map_or(0, |n| n.weights.len());
+        Layer {
+            neurons: (0..nin)
+                .map(|i| Neuron {
+                    weights: other.neurons.iter().map(|n| n.weights[i].clone()).collect(),
+                    bias: Value::new_param(0.0),
+                    non_lin: true,
+                })
+                .collect(),
+        }
+    }
+
     pub fn forward(&self, inputs: &[Value]) -> Vec<Value> {
         self.neurons.iter().map(|n| n.forward(inputs)).collect()
     }
@@ -70,6 +95,114 @@ impl Layer {
     }
 }
 
+// A layer whose parameters are plain f64s. A `forward` call creates leaf `Value`s for them
+// when none exist, and every later forward shares those leaves, so a batch of samples
+// accumulates into the same leaves. `backward` copies their gradients into flat vectors and
+// drops the leaves; the next forward creates fresh ones. Changes to `weights`/`bias` take
+// effect from the next step.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DenseLayer {
+    pub nin: usize,
+    pub nout: usize,
+    // Row-major, `nout` rows of `nin` weights.
+    pub weights: Vec<f64>,
+    pub bias: Vec<f64>,
+    pub non_lin: bool,
+    pub weight_grads: Vec<f64>,
+    pub bias_grads: Vec<f64>,
+    // Leaves of the current step: all weights, then all biases. Empty until the first forward.
+    leaves: RefCell<Vec<Value>>,
+}
+
+impl DenseLayer {
+    pub fn new(nin: usize, nout: usize) -> DenseLayer {
+        let weights = (0..nin * nout).map(|_| rand::thread_rng().gen_range(-1.0..=1.0)).collect();
+        DenseLayer::from_parts(nin, nout, weights, vec![0.0; nout], true)
+    }
+
+    pub fn from_layer(layer: &Layer) -> DenseLayer {
+        let nin = layer.neurons.first().map_or(0, |n| n.weights.len());
+        let weights = layer.neurons.iter().flat_map(|n| n.weights.iter().map(|w| w.data)).collect();
+        let bias = layer.neurons.iter().map(|n| n.bias.data).collect();
+        let non_lin = layer.neurons.first().is_none_or(|n| n.non_lin);
+        DenseLayer::from_parts(nin, layer.neurons.len(), weights, bias, non_lin)
+    }
+
+    fn from_parts(nin: usize, nout: usize, weights: Vec<f64>, bias: Vec<f64>, non_lin: bool) -> DenseLayer {
+        DenseLayer {
+            nin,
+            nout,
+            weights,
+            bias,
+            non_lin,
+            weight_grads: vec![0.0; nin * nout],
+            bias_grads: vec![0.0; nout],
+            leaves: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn forward(&self, inputs: &[Value]) -> Vec<Value> {
+        let mut leaves = self.leaves.borrow_mut();
+        if leaves.is_empty() {
+            leaves.extend(self.weights.iter().chain(self.bias.iter()).map(|&p| Value::new_param(p)));
+        }
+        let (weights, bias) = leaves.split_at(self.weights.len());
+        (0..self.nout)
+            .map(|j| {
+                let row = &weights[j * self.nin..(j + 1) * self.nin];
+                let act = row.iter().zip(inputs.iter()).fold(bias[j].clone(), |acc, (w, i)| acc + w * i);
+                if self.non_lin {
+                    act.tanh()
+                } else {
+                    act
+                }
+            })
+            .collect()
+    }
+
+    // Reads the gradients of the current step's leaves and ends the step. Without a forward
+    // since the last step there are no leaves, and the gradients are all zero.
+    pub fn backward(&mut self, grads: &GradStore) {
+        let leaves = std::mem::take(self.leaves.get_mut());
+        if leaves.is_empty() {
+            self.weight_grads.fill(0.0);
+            self.bias_grads.fill(0.0);
+            return;
+        }
+        let (weights, bias) = leaves.split_at(self.weights.len());
+        for (g, w) in self.weight_grads.iter_mut().zip(weights) {
+            *g = grads.0.get(&w.id).copied().unwrap_or(0.0);
+        }
+        for (g, b) in self.bias_grads.iter_mut().zip(bias) {
+            *g = grads.0.get(&b.id).copied().unwrap_or(0.0);
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LayerStats {
+    // Fraction of outputs with |tanh| above SATURATION_THRESHOLD.
+    pub saturated: f64,
+    // Fraction of outputs that are exactly zero.
+    pub dead: f64,
+}
+
+impl LayerStats {
+    pub const SATURATION_THRESHOLD: f64 = 0.99;
+
+    pub fn from_outputs(outputs: &[Value]) -> LayerStats {
+        if outputs.is_empty() {
+            return LayerStats::default();
+        }
+        let n = outputs.len() as f64;
+        LayerStats {
+            saturated: outputs.iter().filter(|o| o.data.abs() > Self::SATURATION_THRESHOLD).count() as f64 / n,
+            dead: outputs.iter().filter(|o| o.data == 0.0).count() as f64 / n,
+        }
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Clone, Debug, PartialEq)]
 pub struct MLP {
     pub layers: Vec<Layer>,
@@ -94,6 +227,17 @@ impl MLP {
         outputs
     }
 
+    // Only reads `data`, so collecting stats doesn't change the graph.
+    pub fn forward_with_stats(&self, inputs: &[Value]) -> (Vec<Value>, Vec<LayerStats>) {
+        let mut outputs = inputs.to_vec();
+        let mut stats = Vec::with_capacity(self.layers.len());
+        for layer in &self.layers {
+            outputs = layer.forward(&outputs);
+            stats.push(LayerStats::from_outputs(&outputs));
+        }
+        (outputs, stats)
+    }
+
     pub fn parameters(&self) -> Vec<Value> {
         self.layers.iter().flat_map(|l| l.parameters()).collect()
     }
@@ -111,13 +255,386 @@ impl MLP {
             layer.update(layer_params);
         }
     }
+
+    // Memoized evaluation for data with repeated rows. Runs under no_grad, so nothing is
+    // recorded; the cache must be cleared whenever the parameters change.
+    pub fn forward_cached(&self, input: &[f64], cache: &mut HashMap<Vec<OrderedFloat>, Vec<f64>>) -> Vec<f64> {
+        let key = input.iter().map(|&x| OrderedFloat(x)).collect::<Vec<_>>();
+        cache
+            .entry(key)
+            .or_insert_with(|| {
+                #[cfg(test)]
+                test::FORWARD_COMPUTED.with(|n| n.set(n.get() + 1));
+                let inputs = input.iter().map(|&x| Value::new(x)).collect::<Vec<_>>();
+                no_grad(|| self.forward(&inputs)).iter().map(|o| o.data).collect()
+            })
+            .clone()
+    }
+
+    // Text interchange format, one record per line:
+    //   mlp 1
+    //   layer <nin> <nout>
+    //   neuron <tanh|linear> <bias> <w1> ... <w_nin>
+    // with nout neuron lines after each layer line. Numbers use f64's shortest
+    // round-trip formatting, so import(export()) restores the exact weights.
+    pub fn export(&self) -> String {
+        let mut out = String::from("mlp 1\n");
+        for layer in &self.layers {
+            let nin = layer.neurons.first().map_or(0, |n| n.weights.len());
+            out += &format!("layer {} {}\n", nin, layer.neurons.len());
+            for neuron in &layer.neurons {
+                out += if neuron.non_lin { "neuron tanh" } else { "neuron linear" };
+                for p in std::iter::once(&neuron.bias).chain(neuron.weights.iter()) {
+                    out += &format!(" {}", p.data);
+                }
+                out += "\n";
+            }
+        }
+        out
+    }
+
+    pub fn import(text: &str) -> Result<MLP, ImportError> {
+        let mut lines = text.lines().enumerate().map(|(i, l)| (i + 1, l.trim())).filter(|(_, l)| !l.is_empty());
+        if lines.next().map(|(_, l)| l) != Some("mlp 1") {
+            return Err(ImportError::MissingHeader);
+        }
+        // (header line, nin, nout, layer)
+        let mut layers: Vec<(usize, usize, usize, Layer)> = Vec::new();
+        for (line, text) in lines {
+            let malformed = ImportError::Malformed { line };
+            let mut fields = text.split_whitespace();
+            match fields.next() {
+                Some("layer") => {
+                    let dims = fields
+                        .map(|f| f.parse::<usize>())
+                        .collect::<Result<Vec<_>, _>>()
+                        .map_err(|_| malformed.clone())?;
+                    let [nin, nout] = dims[..] else { return Err(malformed) };
+                    // Each layer has to take the previous layer's outputs as inputs.
+                    if layers.last().is_some_and(|&(_, _, prev_nout, _)| prev_nout != nin) {
+                        return Err(malformed);
+                    }
+                    layers.push((line, nin, nout, Layer { neurons: Vec::new() }));
+                }
+                Some("neuron") => {
+                    let (_, nin, _, layer) = layers.last_mut().ok_or(malformed.clone())?;
+                    let non_lin = match fields.next() {
+                        Some("tanh") => true,
+                        Some("linear") => false,
+                        _ => return Err(malformed),
+                    };
+                    let params = fields
+                        .map(|f| f.parse::<f64>())
+                        .collect::<Result<Vec<_>, _>>()
+                        .map_err(|_| malformed.clone())?;
+                    if params.len() != *nin + 1 {
+                        return Err(malformed);
+                    }
+                    layer.neurons.push(Neuron {
+                        weights: params[1..].iter().map(|&w| Value::new_param(w)).collect(),
+                        bias: Value::new_param(params[0]),
+                        non_lin,
+                    });
+                }
+                _ => return Err(malformed),
+            }
+        }
+        layers
+            .into_iter()
+            .map(|(line, _, nout, layer)| {
+                if layer.neurons.len() == nout {
+                    Ok(layer)
+                } else {
+                    Err(ImportError::Malformed { line })
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(|layers| MLP { layers })
+    }
+
+    // Marks the layer's weights and biases frozen, so optimizers leave them alone.
+    pub fn freeze_layer(&mut self, index: usize) {
+        self.set_layer_frozen(index, true);
+    }
+
+    pub fn unfreeze_layer(&mut self, index: usize) {
+        self.set_layer_frozen(index, false);
+    }
+
+    fn set_layer_frozen(&mut self, index: usize, frozen: bool) {
+        for neuron in &mut self.layers[index].neurons {
+            for w in &mut neuron.weights {
+                w.frozen = frozen;
+            }
+            neuron.bias.frozen = frozen;
+        }
+    }
+
+    // One training step: backward from `loss`, step the optimizer and write the results back.
+    pub fn apply_gradients(&mut self, loss: &Value, optimizer: &mut dyn Optimizer) {
+        let grads = loss.backward();
+        let mut parameters = self.parameters();
+        optimizer.step(&mut parameters, &grads);
+        self.update_parameters(parameters);
+    }
+}
+
+// f64 usable as a hash key: equality and hashing go by bit pattern, so NaN equals itself
+// while 0.0 and -0.0 are different keys.
+#[derive(Clone, Copy, Debug)]
+pub struct OrderedFloat(pub f64);
+
+impl PartialEq for OrderedFloat {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.to_bits() == other.0.to_bits()
+    }
+}
+
+impl Eq for OrderedFloat {}
+
+impl Hash for OrderedFloat {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.to_bits().hash(state);
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ImportError {
+    // The text doesn't start with the `mlp 1` header line.
+    MissingHeader,
+    // 1-based line number of a record that can't be parsed or doesn't match its layer.
+    Malformed { line: usize },
+}
+
+impl std::fmt::Display for ImportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImportError::MissingHeader => write!(f, "missing `mlp 1` header"),
+            ImportError::Malformed { line } => write!(f, "malformed model description at line {}", line),
+        }
+    }
+}
+
+impl std::error::Error for ImportError {}
+
+pub fn clip_output_norm(outputs: &[Value], max_norm: f64) -> Vec<Value> {
+    let norm = outputs.iter().fold(Value::default(), |acc, o| acc + o * o).pow(&Value::from(0.5));
+    if norm.data <= max_norm {
+        return outputs.to_vec();
+    }
+    let scale = Value::from(max_norm) / norm;
+    outputs.iter().map(|o| o * &scale).collect()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dim {
+    // One sum per row.
+    Rows,
+    // One sum per column.
+    Cols,
+}
+
+pub fn sum_dim(grid: &[Vec<Value>], dim: Dim) -> Vec<Value> {
+    match dim {
+        Dim::Rows => grid.iter().map(|row| row.iter().fold(Value::default(), |acc, x| acc + x)).collect(),
+        Dim::Cols => {
+            let ncols = grid.first().map_or(0, |row| row.len());
+            (0..ncols).map(|j| grid.iter().fold(Value::default(), |acc, row| acc + &row[j])).collect()
+        }
+    }
+}
+
+pub fn relu_vec(values: &[Value]) -> Vec<Value> {
+    values.iter().map(|v| v.relu()).collect()
+}
+
+pub fn tanh_vec(values: &[Value]) -> Vec<Value> {
+    values.iter().map(|v| v.tanh()).collect()
+}
+
+pub fn sigmoid_vec(values: &[Value]) -> Vec<Value> {
+    values.iter().map(|v| v.sigmoid()).collect()
+}
+
+// Batched activations: the forward data is computed for the whole slice in one plain
+// f64 loop (which the compiler can vectorize), then the ops are recorded per element.
+fn unary_batch(values: &[Value], f: fn(f64) -> f64, op: UnaryOp) -> Vec<Value> {
+    let data = values.iter().map(|v| f(v.data)).collect::<Vec<_>>();
+    values.iter().zip(data).map(|(v, d)| Value::from_op(d, Op::Unary(v.clone(), op.clone()))).collect()
+}
+
+pub fn exp_batch(values: &[Value]) -> Vec<Value> {
+    unary_batch(values, f64::exp, UnaryOp::Exp)
+}
+
+pub fn ln_batch(values: &[Value]) -> Vec<Value> {
+    unary_batch(values, f64::ln, UnaryOp::Ln)
+}
+
+pub fn tanh_batch(values: &[Value]) -> Vec<Value> {
+    unary_batch(values, crate::tanh_data, UnaryOp::Tanh)
+}
+
+pub fn sigmoid_batch(values: &[Value]) -> Vec<Value> {
+    unary_batch(values, crate::sigmoid_data, UnaryOp::Sigmoid)
+}
+
+// Refills `buf` with fresh leaves for `data`, keeping the Vec's allocation across batches.
+pub fn values_from(data: &[f64], buf: &mut Vec<Value>) {
+    buf.clear();
+    buf.extend(data.iter().map(|&x| Value::new(x)));
+}
+
+pub fn copy_data_into(values: &[Value], buf: &mut Vec<f64>) {
+    buf.clear();
+    buf.extend(values.iter().map(|v| v.data));
+}
+
+// Population mean and variance from one pass over `values`, using var = E[d²] - E[d]² with
+// d = x - shift. Shifting by the first value's data keeps E[d²] and E[d]² small when the
+// values sit far from zero, so the subtraction doesn't cancel away the variance. The shift is
+// a constant, so gradients are unchanged.
+pub fn mean_var(values: &[Value]) -> (Value, Value) {
+    assert!(!values.is_empty(), "mean_var needs at least one value");
+    let shift = values[0].data;
+    let (sum, sum_sq) = values.iter().fold((Value::default(), Value::default()), |(sum, sum_sq), v| {
+        let d = v - shift;
+        (sum + &d, sum_sq + &d * &d)
+    });
+    let inv_n = 1.0 / values.len() as f64;
+    let mean_d = sum * inv_n;
+    let var = sum_sq * inv_n - &mean_d * &mean_d;
+    (mean_d + shift, var)
+}
+
+// The max is subtracted as a constant, so gradients are still exactly softmax(values).
+pub fn logsumexp(values: &[Value]) -> Value {
+    let max = values.iter().map(|v| v.data).fold(f64::NEG_INFINITY, f64::max);
+    let sum = values.iter().fold(Value::default(), |acc, v| acc + (v - max).exp());
+    sum.ln() + max
+}
+
+// Identity in forward; the gradient flowing back into `value` is scaled by -lambda.
+pub fn grad_reverse(value: &Value, lambda: f64) -> Value {
+    value.grad_scale(-lambda)
+}
+
+// Pairwise sum with depth log2(n) instead of the n of a left fold.
+pub fn sum_balanced(values: &[Value]) -> Value {
+    match values.len() {
+        0 => Value::default(),
+        1 => values[0].clone(),
+        n => sum_balanced(&values[..n / 2]) + sum_balanced(&values[n / 2..]),
+    }
+}
+
+// Equal-width bins over [min, max] of the values' data, as (bin_center, count) pairs.
+// If every value is equal they all land in the first bin.
+pub fn histogram(values: &[Value], bins: usize) -> Vec<(f64, usize)> {
+    if values.is_empty() || bins == 0 {
+        return Vec::new();
+    }
+    let min = values.iter().map(|v| v.data).fold(f64::INFINITY, f64::min);
+    let max = values.iter().map(|v| v.data).fold(f64::NEG_INFINITY, f64::max);
+    let width = (max - min) / bins as f64;
+    let mut counts = vec![0; bins];
+    for v in values {
+        let i = if width > 0.0 { (((v.data - min) / width) as usize).min(bins - 1) } else { 0 };
+        counts[i] += 1;
+    }
+    counts.into_iter().enumerate().map(|(i, c)| (min + (i as f64 + 0.5) * width, c)).collect()
+}
+
+// Gathered elements are clones of the sources, so gradients land on exactly those nodes
+// and repeated indices accumulate.
+pub fn gather(values: &[Value], indices: &[usize]) -> Vec<Value> {
+    indices.iter().map(|&i| values[i].clone()).collect()
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Standardizer {
+    pub mean: Vec<f64>,
+    pub std: Vec<f64>,
+}
+
+impl Standardizer {
+    // Per-feature mean and population std; constant features get std 1.0 so they map to 0.
+    pub fn fit(data: &[Vec<f64>]) -> Standardizer {
+        let n = data.len() as f64;
+        let nfeatures = data.first().map_or(0, |row| row.len());
+        let mean = (0..nfeatures).map(|j| data.iter().map(|row| row[j]).sum::<f64>() / n).collect::<Vec<_>>();
+        let std = (0..nfeatures)
+            .map(|j| {
+                let std = (data.iter().map(|row| (row[j] - mean[j]).powi(2)).sum::<f64>() / n).sqrt();
+                if std > 0.0 { std } else { 1.0 }
+            })
+            .collect();
+        Standardizer { mean, std }
+    }
+
+    pub fn forward(&self, inputs: &[Value]) -> Vec<Value> {
+        inputs
+            .iter()
+            .zip(self.mean.iter().zip(self.std.iter()))
+            .map(|(x, (mean, std))| (x + (-mean)) * (1.0 / std))
+            .collect()
+    }
+}
+
+// Soft update target <- tau * online + (1 - tau) * target, writing only `data` so the
+// target's parameters stay leaves unconnected to the online network.
+pub fn polyak_update(target: &mut MLP, online: &MLP, tau: f64) {
+    for (t_layer, o_layer) in target.layers.iter_mut().zip(online.layers.iter()) {
+        for (t, o) in t_layer.neurons.iter_mut().zip(o_layer.neurons.iter()) {
+            for (tw, ow) in t.weights.iter_mut().zip(o.weights.iter()) {
+                tw.data = tau * ow.data + (1.0 - tau) * tw.data;
+            }
+            t.bias.data = tau * o.bias.data + (1.0 - tau) * t.bias.data;
+        }
+    }
+}
+
+// Masked positions become fresh constant leaves, so no gradient reaches the originals.
+pub fn masked_fill(values: &[Value], mask: &[bool], fill: f64) -> Vec<Value> {
+    assert_eq!(values.len(), mask.len(), "mask must match values in length");
+    values.iter().zip(mask.iter()).map(|(v, &m)| if m { Value::from(fill) } else { v.clone() }).collect()
+}
+
+pub fn cumsum(values: &[Value]) -> Vec<Value> {
+    let mut out: Vec<Value> = Vec::with_capacity(values.len());
+    for v in values {
+        let next = match out.last() {
+            Some(prev) => prev + v,
+            None => v.clone(),
+        };
+        out.push(next);
+    }
+    out
+}
+
+// (diag(s) - s sᵀ) v for a softmax output s, computed directly on f64s.
+pub fn softmax_jvp(softmax_out: &[f64], v: &[f64]) -> Vec<f64> {
+    let dot = softmax_out.iter().zip(v.iter()).map(|(s, v)| s * v).sum::<f64>();
+    softmax_out.iter().zip(v.iter()).map(|(s, v)| s * (v - dot)).collect()
 }
 
 #[cfg(test)]
 mod test {
-    use crate::nn::{Layer, MLP};
+    use std::cell::Cell;
+    use std::collections::HashMap;
+    use crate::nn::{clip_output_norm, copy_data_into, cumsum, exp_batch, gather, grad_reverse, histogram, ln_batch, logsumexp,
+                    masked_fill, mean_var, polyak_update, relu_vec, sigmoid_batch, sigmoid_vec, softmax_jvp, sum_balanced, sum_dim,
+                    tanh_batch, tanh_vec, values_from, DenseLayer, Dim, ImportError, Layer, Neuron, OrderedFloat, Standardizer,
+                    MLP};
+    use crate::optim::SGD;
+    use crate::{Op, UnaryOp};
     use crate::Value;
 
+    thread_local! {
+        // Number of cache misses in MLP::forward_cached on this test thread.
+        pub(crate) static FORWARD_COMPUTED: Cell<usize> = const { Cell::new(0) };
+    }
+
     #[test]
     fn test_layer() {
         let x = [Value::from(2.0_f64), Value::from(3.0_f64)];
@@ -150,7 +667,11 @@ mod test {
 
         for k in 0..20 {
             let ypred = xs.iter().map(|x| n.forward(x)).collect::<Vec<_>>();
-            let loss = ypred.iter().zip(ys.iter()).map(|(yp, y)| (&yp[0] - y).sqrt()).fold(Value::default(), |acc, x| acc + x);
+            let loss = ypred
+                .iter()
+                .zip(ys.iter())
+                .map(|(yp, y)| (&yp[0] - y).pow(&Value::from(2.0)))
+                .fold(Value::default(), |acc, x| acc + x);
             let grad_store = loss.backward();
 
             let mut parameters = n.parameters();
@@ -163,4 +684,501 @@ mod test {
             println!("{k}, loss: {}", loss.data);
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_clip_output_norm() {
+        let outputs = [Value::new(3.0), Value::new(4.0)];
+        let clipped = clip_output_norm(&outputs, 1.0);
+        let norm = clipped.iter().map(|o| o.data * o.data).sum::<f64>().sqrt();
+        assert!((norm - 1.0).abs() < 1e-12);
+        assert!((clipped[0].data - 0.6).abs() < 1e-12);
+        assert!((clipped[1].data - 0.8).abs() < 1e-12);
+
+        let unchanged = clip_output_norm(&outputs, 10.0);
+        assert_eq!(unchanged[0].data, 3.0);
+        assert_eq!(unchanged[1].data, 4.0);
+    }
+
+    #[test]
+    fn test_sum_dim() {
+        let grid = vec![vec![Value::new(1.0), Value::new(2.0), Value::new(3.0)],
+            vec![Value::new(4.0), Value::new(5.0), Value::new(6.0)]];
+
+        let rows = sum_dim(&grid, Dim::Rows);
+        assert_eq!(rows.iter().map(|v| v.data).collect::<Vec<_>>(), vec![6.0, 15.0]);
+        let g = (&rows[0] * 2.0 + &rows[1]).backward();
+        for (i, row) in grid.iter().enumerate() {
+            for x in row {
+                assert_eq!(g.0[&x.id], if i == 0 { 2.0 } else { 1.0 });
+            }
+        }
+
+        let cols = sum_dim(&grid, Dim::Cols);
+        assert_eq!(cols.iter().map(|v| v.data).collect::<Vec<_>>(), vec![5.0, 7.0, 9.0]);
+        let g = (&cols[0] + &cols[1] * 2.0 + &cols[2] * 3.0).backward();
+        for row in &grid {
+            for (j, x) in row.iter().enumerate() {
+                assert_eq!(g.0[&x.id], (j + 1) as f64);
+            }
+        }
+    }
+
+    #[test]
+    fn test_activation_vec() {
+        let xs = [Value::new(-2.0), Value::new(0.5), Value::new(-0.1), Value::new(3.0)];
+
+        let relu = relu_vec(&xs);
+        assert_eq!(relu.iter().map(|v| v.data).collect::<Vec<_>>(), vec![0.0, 0.5, 0.0, 3.0]);
+        let g = relu.iter().fold(Value::default(), |acc, x| acc + x).backward();
+        assert_eq!(xs.iter().map(|x| g.0[&x.id]).collect::<Vec<_>>(), vec![0.0, 1.0, 0.0, 1.0]);
+
+        let tanh = tanh_vec(&xs);
+        let sigmoid = sigmoid_vec(&xs);
+        for ((x, t), s) in xs.iter().zip(tanh.iter()).zip(sigmoid.iter()) {
+            assert!((t.data - x.data.tanh()).abs() < 1e-12);
+            assert!((s.data - 1.0 / (1.0 + (-x.data).exp())).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_logsumexp() {
+        let data = [1.0, 2.0, 3.0];
+        let xs = data.iter().map(|&x| Value::new(x)).collect::<Vec<_>>();
+        let lse = logsumexp(&xs);
+        let expected = data.iter().map(|x| x.exp()).sum::<f64>().ln();
+        assert!((lse.data - expected).abs() < 1e-12);
+
+        let g = lse.backward();
+        let eps = 1e-6;
+        for i in 0..data.len() {
+            let mut plus = data;
+            plus[i] += eps;
+            let mut minus = data;
+            minus[i] -= eps;
+            let f = |d: [f64; 3]| logsumexp(&d.iter().map(|&x| Value::new(x)).collect::<Vec<_>>()).data;
+            let numeric = (f(plus) - f(minus)) / (2.0 * eps);
+            let softmax = data[i].exp() / data.iter().map(|x| x.exp()).sum::<f64>();
+            assert!((g.0[&xs[i].id] - numeric).abs() < 1e-6);
+            assert!((g.0[&xs[i].id] - softmax).abs() < 1e-12);
+        }
+
+        let large = [Value::new(1000.0), Value::new(1000.0)];
+        assert!((logsumexp(&large).data - (1000.0 + 2.0_f64.ln())).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_forward_detailed() {
+        let x = [Value::new(0.5), Value::new(-1.5)];
+        let n = Neuron::new(2, true);
+        let (pre, act) = n.forward_detailed(&x);
+        assert!(act.same_value(&pre.tanh()));
+        assert_eq!(act.op, Some(Op::Unary(pre.clone(), UnaryOp::Tanh)));
+
+        let g = act.backward();
+        let g_pre = pre.backward();
+        let local = 1.0 - act.data.powi(2);
+        for w in &n.weights {
+            assert!((g.0[&w.id] - local * g_pre.0[&w.id]).abs() < 1e-12);
+        }
+
+        let linear = Neuron::new(2, false);
+        let (pre, act) = linear.forward_detailed(&x);
+        assert!(pre.same_node(&act));
+    }
+
+    #[test]
+    fn test_grad_reverse() {
+        let x = Value::new(0.7);
+        let r = grad_reverse(&x, 0.5);
+        assert_eq!(r.data, x.data);
+
+        let y = (&r * 3.0).tanh();
+        let upstream = 3.0 * (1.0 - y.data.powi(2));
+        let g = y.backward();
+        assert!((g.0[&x.id] - (-0.5 * upstream)).abs() < 1e-12);
+        assert!(matches!(r.op, Some(Op::Unary(_, UnaryOp::GradScale(k))) if k == -0.5));
+    }
+
+    #[test]
+    fn test_forward_with_stats() {
+        let mut n = MLP::new(2, &[3, 2]);
+        let parameters = n.layers[0].parameters().iter().map(|_| Value::new(10.0))
+            .chain(n.layers[1].parameters().iter().map(|_| Value::new(0.01)))
+            .collect();
+        n.update_parameters(parameters);
+
+        let x = [Value::new(5.0), Value::new(5.0)];
+        let (outputs, stats) = n.forward_with_stats(&x);
+        for (o, expected) in outputs.iter().zip(n.forward(&x).iter()) {
+            assert!(o.same_value(expected));
+        }
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].saturated, 1.0);
+        assert_eq!(stats[1].saturated, 0.0);
+        assert_eq!(stats[1].dead, 0.0);
+    }
+
+    #[test]
+    fn test_sum_balanced() {
+        let xs = (0..16).map(|i| Value::new(i as f64 * 0.5)).collect::<Vec<_>>();
+        let naive = xs.iter().fold(Value::default(), |acc, x| acc + x);
+        let balanced = sum_balanced(&xs);
+        assert_eq!(balanced.data, naive.data);
+        assert_eq!(balanced.graph_depth(), 4);
+        assert_eq!(naive.graph_depth(), 16);
+
+        let g = balanced.backward();
+        assert!(xs.iter().all(|x| g.0[&x.id] == 1.0));
+    }
+
+    #[test]
+    fn test_histogram() {
+        let values = [0.0, 0.5, 1.0, 1.5, 2.0, 3.9, 4.0].map(Value::new);
+        let hist = histogram(&values, 4);
+        assert_eq!(hist, vec![(0.5, 2), (1.5, 2), (2.5, 1), (3.5, 2)]);
+
+        let same = [2.0, 2.0].map(Value::new);
+        assert_eq!(histogram(&same, 3), vec![(2.0, 2), (2.0, 0), (2.0, 0)]);
+        assert!(histogram(&[], 3).is_empty());
+    }
+
+    #[test]
+    fn test_dense_layer() {
+        let layer = Layer::new(3, 2);
+        let mut dense = DenseLayer::from_layer(&layer);
+        let xs = [[Value::new(0.5), Value::new(-1.0), Value::new(2.0)],
+            [Value::new(-0.3), Value::new(0.8), Value::new(1.1)]];
+
+        let expected = xs.iter().flat_map(|x| layer.forward(x)).collect::<Vec<_>>();
+        let outputs = xs.iter().flat_map(|x| dense.forward(x)).collect::<Vec<_>>();
+        for (o, e) in outputs.iter().zip(expected.iter()) {
+            assert!((o.data - e.data).abs() < 1e-12);
+        }
+
+        let g_layer = expected.iter().fold(Value::default(), |acc, o| acc + o).backward();
+        let g_dense = outputs.iter().fold(Value::default(), |acc, o| acc + o).backward();
+        dense.backward(&g_dense);
+        for (j, neuron) in layer.neurons.iter().enumerate() {
+            for (i, w) in neuron.weights.iter().enumerate() {
+                assert!((dense.weight_grads[j * 3 + i] - g_layer.0[&w.id]).abs() < 1e-12);
+            }
+            assert!((dense.bias_grads[j] - g_layer.0[&neuron.bias.id]).abs() < 1e-12);
+        }
+
+        // A backward without a forward since the last step has no leaves to read.
+        dense.backward(&g_dense);
+        assert!(dense.weight_grads.iter().chain(dense.bias_grads.iter()).all(|&g| g == 0.0));
+        let mut fresh = DenseLayer::new(3, 2);
+        fresh.backward(&g_dense);
+        assert!(fresh.weight_grads.iter().chain(fresh.bias_grads.iter()).all(|&g| g == 0.0));
+
+        // The next step builds fresh leaves from the updated weights.
+        dense.weights[0] += 1.0;
+        let shifted = dense.forward(&xs[0]);
+        assert!((shifted[0].data - outputs[0].data).abs() > 1e-6);
+    }
+
+    #[test]
+    fn test_gather() {
+        let values = [Value::new(1.0), Value::new(2.0), Value::new(3.0)];
+        let picked = gather(&values, &[2, 0, 2]);
+        assert_eq!(picked.iter().map(|v| v.data).collect::<Vec<_>>(), vec![3.0, 1.0, 3.0]);
+
+        let loss = &picked[0] * 2.0 + &picked[1] * 5.0 + &picked[2] * 7.0;
+        let g = loss.backward();
+        assert_eq!(g.0[&values[0].id], 5.0);
+        assert!(!g.0.contains_key(&values[1].id));
+        assert_eq!(g.0[&values[2].id], 9.0);
+    }
+
+    #[test]
+    fn test_tied_layer() {
+        let encoder = Layer::new(3, 2);
+        let decoder = Layer::tied_from(&encoder);
+        assert_eq!(decoder.neurons.len(), 3);
+        for (i, neuron) in decoder.neurons.iter().enumerate() {
+            for (j, w) in neuron.weights.iter().enumerate() {
+                assert!(w.same_node(&encoder.neurons[j].weights[i]));
+            }
+        }
+
+        let x = [Value::new(0.5), Value::new(-1.0), Value::new(2.0)];
+        let hidden = encoder.forward(&x);
+        let out = decoder.forward(&hidden).iter().fold(Value::default(), |acc, o| acc + o);
+        let both = out.backward();
+
+        let sum = |outputs: Vec<Value>| outputs.iter().fold(Value::default(), |acc, o| acc + o);
+        let detached_hidden = hidden.iter().map(|h| h.detach()).collect::<Vec<_>>();
+        let decoder_only = sum(decoder.forward(&detached_hidden)).backward();
+        let mut untied = decoder.clone();
+        for neuron in &mut untied.neurons {
+            neuron.weights = neuron.weights.iter().map(|w| w.detach()).collect();
+        }
+        let encoder_only = sum(untied.forward(&hidden)).backward();
+
+        for w in encoder.neurons.iter().flat_map(|n| n.weights.iter()) {
+            assert!((both.0[&w.id] - (decoder_only.0[&w.id] + encoder_only.0[&w.id])).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_polyak_update() {
+        let online = MLP::new(2, &[3, 1]);
+        let mut target = MLP::new(2, &[3, 1]);
+        let before = target.parameters();
+
+        polyak_update(&mut target, &online, 0.25);
+        for ((t, b), o) in target.parameters().iter().zip(before.iter()).zip(online.parameters().iter()) {
+            assert!((t.data - (0.25 * o.data + 0.75 * b.data)).abs() < 1e-12);
+            assert!(t.same_node(b));
+            assert!(t.op.is_none());
+        }
+
+        let x = [Value::new(0.5), Value::new(-1.0)];
+        let g = target.forward(&x)[0].backward();
+        assert!(online.parameters().iter().all(|p| !g.0.contains_key(&p.id)));
+        assert!(target.parameters().iter().all(|p| g.0.contains_key(&p.id)));
+    }
+
+    #[test]
+    fn test_masked_fill() {
+        let values = [Value::new(1.0), Value::new(2.0), Value::new(3.0)];
+        let filled = masked_fill(&values, &[false, true, false], f64::NEG_INFINITY);
+        assert_eq!(filled[1].data, f64::NEG_INFINITY);
+        assert!(filled[0].same_node(&values[0]));
+
+        let loss = &filled[0] * 2.0 + filled[1].exp() + &filled[2] * 3.0;
+        let g = loss.backward();
+        assert_eq!(g.0[&values[0].id], 2.0);
+        assert!(!g.0.contains_key(&values[1].id));
+        assert_eq!(g.0[&values[2].id], 3.0);
+    }
+
+    #[test]
+    fn test_cumsum() {
+        let xs = [1.0, 2.0, 3.0, 4.0].map(Value::new);
+        let sums = cumsum(&xs);
+        assert_eq!(sums.iter().map(|v| v.data).collect::<Vec<_>>(), vec![1.0, 3.0, 6.0, 10.0]);
+
+        let g = Value::backward_multi(&sums);
+        assert_eq!(xs.iter().map(|x| g.0[&x.id]).collect::<Vec<_>>(), vec![4.0, 3.0, 2.0, 1.0]);
+    }
+
+    #[test]
+    fn test_softmax_jvp() {
+        fn softmax(z: &[f64]) -> Vec<f64> {
+            let sum = z.iter().map(|x| x.exp()).sum::<f64>();
+            z.iter().map(|x| x.exp() / sum).collect()
+        }
+
+        let z = [0.2, -1.0, 1.5, 0.3];
+        let v = [1.0, 0.5, -2.0, 0.25];
+        let eps = 1e-6;
+        let plus = softmax(&z.iter().zip(v.iter()).map(|(z, v)| z + eps * v).collect::<Vec<_>>());
+        let minus = softmax(&z.iter().zip(v.iter()).map(|(z, v)| z - eps * v).collect::<Vec<_>>());
+
+        let jvp = softmax_jvp(&softmax(&z), &v);
+        for i in 0..z.len() {
+            let numeric = (plus[i] - minus[i]) / (2.0 * eps);
+            assert!((jvp[i] - numeric).abs() < 1e-8);
+        }
+    }
+
+    #[test]
+    fn test_standardizer() {
+        let data = vec![vec![1.0, 10.0, 5.0], vec![2.0, 20.0, 5.0], vec![3.0, 60.0, 5.0], vec![6.0, 30.0, 5.0]];
+        let standardizer = Standardizer::fit(&data);
+        let transformed = data
+            .iter()
+            .map(|row| standardizer.forward(&row.iter().map(|&x| Value::new(x)).collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+
+        for j in 0..3 {
+            let column = transformed.iter().map(|row| row[j].data).collect::<Vec<_>>();
+            let mean = column.iter().sum::<f64>() / column.len() as f64;
+            let var = column.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / column.len() as f64;
+            assert!(mean.abs() < 1e-12);
+            assert!((var - if j == 2 { 0.0 } else { 1.0 }).abs() < 1e-12);
+        }
+
+        let x = Value::new(4.0);
+        let g = standardizer.forward(std::slice::from_ref(&x))[0].backward();
+        assert!((g.0[&x.id] - 1.0 / standardizer.std[0]).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_batch_activations() {
+        let xs = (0..1024).map(|i| Value::new(i as f64 / 256.0 - 2.0)).collect::<Vec<_>>();
+        let pos = xs.iter().map(|x| Value::new(x.data.abs() + 0.1)).collect::<Vec<_>>();
+
+        let check = |inputs: &[Value], batched: Vec<Value>, single: Vec<Value>| {
+            for ((x, b), s) in inputs.iter().zip(batched.iter()).zip(single.iter()) {
+                assert_eq!(b.data, s.data);
+                assert_eq!(b.op, s.op);
+                assert_eq!(b.backward().0[&x.id], s.backward().0[&x.id]);
+            }
+        };
+        check(&xs, exp_batch(&xs), xs.iter().map(|x| x.exp()).collect());
+        check(&pos, ln_batch(&pos), pos.iter().map(|x| x.ln()).collect());
+        check(&xs, tanh_batch(&xs), tanh_vec(&xs));
+        check(&xs, sigmoid_batch(&xs), sigmoid_vec(&xs));
+
+        // Timing is only reported, not asserted, so the test stays stable on loaded machines.
+        let start = std::time::Instant::now();
+        for _ in 0..20 {
+            std::hint::black_box(tanh_vec(&xs));
+        }
+        let single = start.elapsed();
+        let start = std::time::Instant::now();
+        for _ in 0..20 {
+            std::hint::black_box(tanh_batch(&xs));
+        }
+        let batched = start.elapsed();
+        println!("tanh on 1024 values: per-element {single:?}, batched {batched:?}");
+    }
+
+    #[test]
+    fn test_apply_gradients() {
+        let mut n = MLP::new(2, &[3, 1]);
+        let x = [Value::new(0.5), Value::new(-1.0)];
+        let loss = |n: &MLP| (&n.forward(&x)[0] - 0.7).pow(&Value::from(2.0));
+
+        let before = n.parameters();
+        let l0 = loss(&n);
+        let grads = l0.backward();
+        n.apply_gradients(&l0, &mut SGD::new(0.1));
+
+        for (p, b) in n.parameters().iter().zip(before.iter()) {
+            assert!((p.data - (b.data - 0.1 * grads.0[&b.id])).abs() < 1e-12);
+        }
+        assert!(loss(&n).data < l0.data);
+    }
+
+    #[test]
+    fn test_values_from_buffers() {
+        let mut values = Vec::new();
+        values_from(&[1.0, 2.0, 3.0, 4.0], &mut values);
+        let old = values.clone();
+        let capacity = values.capacity();
+
+        values_from(&[5.0, 6.0], &mut values);
+        assert_eq!(values.iter().map(|v| v.data).collect::<Vec<_>>(), vec![5.0, 6.0]);
+        assert_eq!(values.capacity(), capacity);
+        assert!(values.iter().all(|v| v.op.is_none() && old.iter().all(|o| !o.same_node(v))));
+
+        let mut data = vec![0.0; 8];
+        copy_data_into(&values, &mut data);
+        assert_eq!(data, vec![5.0, 6.0]);
+        copy_data_into(&old, &mut data);
+        assert_eq!(data, vec![1.0, 2.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn test_export_import() {
+        let mut n = MLP::new(3, &[4, 4, 1]);
+        n.layers[2].neurons[0].non_lin = false;
+        let text = n.export();
+        assert!(text.starts_with("mlp 1\nlayer 3 4\nneuron tanh "));
+
+        let imported = MLP::import(&text).unwrap();
+        assert_eq!(imported.export(), text);
+        let x = [Value::new(2.0), Value::new(3.0), Value::new(-1.0)];
+        assert_eq!(imported.forward(&x)[0].data, n.forward(&x)[0].data);
+        assert!(imported.parameters().iter().all(|p| p.trainable));
+
+        assert_eq!(MLP::import("layer 1 1"), Err(ImportError::MissingHeader));
+        assert_eq!(MLP::import("mlp 1\nlayer 2 1\nneuron tanh 0.0 1.0"), Err(ImportError::Malformed { line: 3 }));
+        assert_eq!(MLP::import("mlp 1\nlayer 1 2\nneuron tanh 0.0 1.0"), Err(ImportError::Malformed { line: 2 }));
+        assert_eq!(
+            MLP::import("mlp 1\nlayer 1 1\nneuron tanh 0.0 1.0\nlayer 2 1\nneuron linear 0.0 1.0 1.0"),
+            Err(ImportError::Malformed { line: 4 })
+        );
+    }
+
+    #[test]
+    fn test_mean_var() {
+        let data = [1.5, -2.0, 0.25, 4.0, 3.0];
+        let xs = data.map(Value::new);
+        let (mean, var) = mean_var(&xs);
+        let expected_mean = data.iter().sum::<f64>() / 5.0;
+        assert!((mean.data - expected_mean).abs() < 1e-12);
+        assert!((var.data - data.iter().map(|x| (x - expected_mean).powi(2)).sum::<f64>() / 5.0).abs() < 1e-12);
+
+        let (g_mean, g_var) = (mean.backward(), var.backward());
+        let eps = 1e-6;
+        for i in 0..data.len() {
+            let at = |d: f64| {
+                let mut shifted = data;
+                shifted[i] += d;
+                let (m, v) = mean_var(&shifted.map(Value::new));
+                (m.data, v.data)
+            };
+            let ((m_plus, v_plus), (m_minus, v_minus)) = (at(eps), at(-eps));
+            assert!((g_mean.0[&xs[i].id] - (m_plus - m_minus) / (2.0 * eps)).abs() < 1e-6);
+            assert!((g_var.0[&xs[i].id] - (v_plus - v_minus) / (2.0 * eps)).abs() < 1e-6);
+        }
+
+        // Data far from zero keeps its variance instead of cancelling to 0 or below.
+        let (mean, var) = mean_var(&[1e8 + 1.0, 1e8 + 2.0, 1e8 + 3.0].map(Value::new));
+        assert_eq!(mean.data, 1e8 + 2.0);
+        assert!((var.data - 2.0 / 3.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_freeze_layer() {
+        let mut n = MLP::new(2, &[3, 3, 1]);
+        n.freeze_layer(0);
+        assert!(n.layers[0].parameters().iter().all(|p| p.frozen));
+
+        let x = [Value::new(0.5), Value::new(-1.0)];
+        let before = n.parameters();
+        let mut opt = SGD::new(0.1);
+        for _ in 0..5 {
+            let loss = (&n.forward(&x)[0] - 0.7).pow(&Value::from(2.0));
+            n.apply_gradients(&loss, &mut opt);
+        }
+
+        let frozen = n.layers[0].parameters().len();
+        for (i, (p, b)) in n.parameters().iter().zip(before.iter()).enumerate() {
+            assert_eq!(p.id, b.id);
+            if i < frozen {
+                assert_eq!(p.data, b.data);
+            }
+        }
+        assert!(n.parameters()[frozen..].iter().zip(before[frozen..].iter()).any(|(p, b)| p.data != b.data));
+
+        n.unfreeze_layer(0);
+        assert!(n.parameters().iter().all(|p| !p.frozen));
+    }
+
+    #[test]
+    fn test_forward_cached() {
+        let n = MLP::new(3, &[4, 4, 1]);
+        let rows = [[2.0, 3.0, -1.0], [0.5, 1.0, 1.0], [2.0, 3.0, -1.0], [2.0, 3.0, -1.0]];
+        let mut cache: HashMap<Vec<OrderedFloat>, Vec<f64>> = HashMap::new();
+
+        FORWARD_COMPUTED.with(|c| c.set(0));
+        let outputs = rows.iter().map(|row| n.forward_cached(row, &mut cache)).collect::<Vec<_>>();
+        assert_eq!(FORWARD_COMPUTED.with(|c| c.get()), 2);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(outputs[0], outputs[2]);
+        assert_eq!(outputs[0], outputs[3]);
+
+        for (row, out) in rows.iter().zip(outputs.iter()) {
+            let expected = n.forward(&row.map(Value::new)).iter().map(|o| o.data).collect::<Vec<_>>();
+            assert_eq!(*out, expected);
+        }
+    }
+
+    #[test]
+    fn test_neuron_large_weights() {
+        let mut n = Neuron::new(2, true);
+        n.update(vec![Value::new_param(1e3), Value::new_param(-2e3)], Value::new_param(5.0));
+        let x = [Value::new(1e3), Value::new(-1e3)];
+        let out = n.forward(&x);
+        assert_eq!(out.data, 1.0);
+
+        let grads = out.backward();
+        assert!(n.parameters().iter().chain(x.iter()).all(|p| grads.0[&p.id].is_finite()));
+    }
+}
@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+use crate::{GradStore, Value, ValueId};
+
+// Implementations leave frozen parameters (see MLP::freeze_layer) untouched.
+pub trait Optimizer {
+    fn step(&mut self, params: &mut [Value], grads: &GradStore);
+}
+
+// Returns new leaves holding `p - lr * grad`; the given parameters are left untouched.
+// Frozen parameters are copied with their data unchanged.
+pub fn sgd_step(params: &[Value], grads: &GradStore, lr: f64) -> Vec<Value> {
+    params
+        .iter()
+        .map(|p| {
+            let g = if p.frozen { 0.0 } else { grads.0.get(&p.id).copied().unwrap_or(0.0) };
+            let mut updated = Value::new_with_label(p.data - lr * g, &p.label);
+            updated.trainable = p.trainable;
+            updated.frozen = p.frozen;
+            updated
+        })
+        .collect()
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Debug, PartialEq)]
+pub struct SGD {
+    pub lr: f64,
+    // Per-parameter learning rates from `with_groups`; other parameters use `lr`.
+    pub groups: HashMap<ValueId, f64>,
+    // When set, each parameter moves by at most this much per step. Private so that it can
+    // only be set through with_max_step, which rejects negative and NaN values.
+    max_step: Option<f64>,
+}
+
+impl SGD {
+    pub fn new(lr: f64) -> SGD {
+        SGD { lr, groups: HashMap::new(), max_step: None }
+    }
+
+    // Parameters listed in a group use that group's learning rate instead of `lr`. A
+    // parameter listed in several groups takes the first one's rate.
+    pub fn with_groups(lr: f64, groups: Vec<(Vec<Value>, f64)>) -> SGD {
+        let mut by_id = HashMap::new();
+        for (params, group_lr) in groups {
+            for p in params {
+                by_id.entry(p.id).or_insert(group_lr);
+            }
+        }
+        SGD { lr, groups: by_id, max_step: None }
+    }
+
+    pub fn with_max_step(self, max_step: f64) -> SGD {
+        assert!(max_step >= 0.0, "max_step must be a non-negative number, got {}", max_step);
+        SGD { max_step: Some(max_step), ..self }
+    }
+
+    fn lr_for(&self, id: ValueId) -> f64 {
+        self.groups.get(&id).copied().unwrap_or(self.lr)
+    }
+}
+
+impl Optimizer for SGD {
+    fn step(&mut self, params: &mut [Value], grads: &GradStore) {
+        for p in params.iter_mut().filter(|p| !p.frozen) {
+            let g = grads.0.get(&p.id).copied().unwrap_or(0.0);
+            let mut delta = -self.lr_for(p.id) * g;
+            if let Some(max_step) = self.max_step {
+                delta = delta.clamp(-max_step, max_step);
+            }
+            p.data += delta;
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Adadelta {
+    pub rho: f64,
+    pub eps: f64,
+    pub sq_grads: HashMap<ValueId, f64>,
+    pub sq_deltas: HashMap<ValueId, f64>,
+}
+
+impl Default for Adadelta {
+    fn default() -> Self {
+        Self::new(0.9, 1e-6)
+    }
+}
+
+impl Adadelta {
+    pub fn new(rho: f64, eps: f64) -> Adadelta {
+        Adadelta {
+            rho,
+            eps,
+            sq_grads: HashMap::new(),
+            sq_deltas: HashMap::new(),
+        }
+    }
+}
+
+impl Optimizer for Adadelta {
+    fn step(&mut self, params: &mut [Value], grads: &GradStore) {
+        for p in params.iter_mut().filter(|p| !p.frozen) {
+            let g = grads.0.get(&p.id).copied().unwrap_or(0.0);
+            let sq_grad = self.sq_grads.entry(p.id).or_insert(0.0);
+            *sq_grad = self.rho * *sq_grad + (1.0 - self.rho) * g * g;
+            let sq_delta = self.sq_deltas.entry(p.id).or_insert(0.0);
+            let delta = -(*sq_delta + self.eps).sqrt() / (*sq_grad + self.eps).sqrt() * g;
+            *sq_delta = self.rho * *sq_delta + (1.0 - self.rho) * delta * delta;
+            p.data += delta;
+        }
+    }
+}
+
+// Runs `inner` as the fast optimizer and, every k-th step, moves the slow weights by
+// `alpha` toward the fast ones and resets the parameters to them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LookAhead<O: Optimizer> {
+    pub inner: O,
+    pub k: usize,
+    pub alpha: f64,
+    pub slow: HashMap<ValueId, f64>,
+    pub steps: usize,
+}
+
+impl<O: Optimizer> LookAhead<O> {
+    pub fn new(inner: O, k: usize, alpha: f64) -> LookAhead<O> {
+        assert!(k > 0, "LookAhead needs k > 0 fast steps between syncs");
+        LookAhead {
+            inner,
+            k,
+            alpha,
+            slow: HashMap::new(),
+            steps: 0,
+        }
+    }
+}
+
+impl<O: Optimizer> Optimizer for LookAhead<O> {
+    fn step(&mut self, params: &mut [Value], grads: &GradStore) {
+        for p in params.iter().filter(|p| !p.frozen) {
+            self.slow.entry(p.id).or_insert(p.data);
+        }
+        self.inner.step(params, grads);
+        self.steps += 1;
+        if self.steps.is_multiple_of(self.k) {
+            for p in params.iter_mut().filter(|p| !p.frozen) {
+                let slow = self.slow.get_mut(&p.id).unwrap();
+                *slow += self.alpha * (p.data - *slow);
+                p.data = *slow;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::nn::MLP;
+    use crate::optim::{sgd_step, Adadelta, LookAhead, Optimizer, SGD};
+    use crate::Value;
+
+    #[test]
+    fn test_adadelta_step() {
+        let mut params = [Value::new(1.0)];
+        let loss = &params[0] * 2.0;
+        let grads = loss.backward();
+
+        let mut opt = Adadelta::new(0.9, 1e-6);
+        opt.step(&mut params, &grads);
+
+        let sq_grad = 0.1 * 2.0 * 2.0;
+        let delta = -(1e-6_f64).sqrt() / (sq_grad + 1e-6_f64).sqrt() * 2.0;
+        assert!((opt.sq_grads[&params[0].id] - sq_grad).abs() < 1e-12);
+        assert!((opt.sq_deltas[&params[0].id] - 0.1 * delta * delta).abs() < 1e-12);
+        assert!((params[0].data - (1.0 + delta)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_adadelta_mlp() {
+        let mut n = MLP::new(3, &[4, 4, 1]);
+        let xs = [[Value::new(2.0), Value::new(3.0), Value::new(-1.0)],
+            [Value::new(3.0), Value::new(-1.0), Value::new(0.5)],
+            [Value::new(0.5), Value::new(1.0), Value::new(1.0)],
+            [Value::new(1.0), Value::new(1.0), Value::new(-1.0)]];
+        let ys = [Value::new(1.0), Value::new(-1.0), Value::new(-1.0), Value::new(1.0)];
+
+        let mut opt = Adadelta::default();
+        let mut losses = Vec::new();
+        for _ in 0..100 {
+            let ypred = xs.iter().map(|x| n.forward(x)).collect::<Vec<_>>();
+            let loss = ypred
+                .iter()
+                .zip(ys.iter())
+                .map(|(yp, y)| (&yp[0] - y).pow(&Value::from(2.0)))
+                .fold(Value::default(), |acc, x| acc + x);
+            let grad_store = loss.backward();
+
+            let mut parameters = n.parameters();
+            opt.step(&mut parameters, &grad_store);
+            n.update_parameters(parameters);
+            losses.push(loss.data);
+        }
+        assert!(losses.last().unwrap() < losses.first().unwrap());
+    }
+
+    #[test]
+    fn test_sgd_groups() {
+        let a = Value::new(1.0);
+        let b = Value::new(1.0);
+        let c = Value::new(1.0);
+        let loss = &a * 2.0 + &b * 3.0 + &c * 4.0;
+        let grads = loss.backward();
+
+        let mut opt = SGD::with_groups(0.1, vec![(vec![a.clone()], 0.01), (vec![b.clone()], 1.0)]);
+        let mut params = [a, b, c];
+        opt.step(&mut params, &grads);
+        // The groups only keep ids, so the parameters stay unshared and are updated in place.
+        assert!(params.iter_mut().all(|p| p.data_mut().is_some()));
+        assert!((params[0].data - (1.0 - 0.01 * 2.0)).abs() < 1e-12);
+        assert!((params[1].data - (1.0 - 1.0 * 3.0)).abs() < 1e-12);
+        assert!((params[2].data - (1.0 - 0.1 * 4.0)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_sgd_step() {
+        let a = Value::new(1.0);
+        let b = Value::new(-2.0);
+        let loss = &a * &b;
+        let grads = loss.backward();
+
+        let updated = sgd_step(&[a.clone(), b.clone()], &grads, 0.05);
+        assert_eq!(updated[0].data, 1.0 - 0.05 * -2.0);
+        assert_eq!(updated[1].data, -2.0 - 0.05 * 1.0);
+        assert!(updated.iter().all(|p| p.op.is_none()));
+        assert!(!updated[0].same_node(&a));
+        assert_eq!(a.data, 1.0);
+        assert_eq!(b.data, -2.0);
+    }
+
+    #[test]
+    fn test_sgd_max_step() {
+        let mut opt = SGD::new(1.0).with_max_step(0.1);
+        let mut params = [Value::new(1.0), Value::new(-1.0), Value::new(0.5)];
+        for _ in 0..5 {
+            let loss = &params[0] * 1000.0 + &params[1] * &params[1] * -50.0 + &params[2] * 0.01;
+            let grads = loss.backward();
+            let before = params.iter().map(|p| p.data).collect::<Vec<_>>();
+            opt.step(&mut params, &grads);
+            for (p, b) in params.iter().zip(before.iter()) {
+                assert!((p.data - b).abs() <= 0.1 + 1e-12);
+            }
+            assert!((params[2].data - (before[2] - 0.01)).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_lookahead() {
+        let mut opt = LookAhead::new(SGD::new(0.1), 3, 0.5);
+        let mut params = [Value::new(2.0)];
+
+        let mut fast = 2.0;
+        for _ in 0..3 {
+            let loss = &params[0] * &params[0];
+            let grads = loss.backward();
+            opt.step(&mut params, &grads);
+            fast -= 0.1 * 2.0 * fast;
+            if opt.steps < 3 {
+                assert!((params[0].data - fast).abs() < 1e-12);
+                assert_eq!(opt.slow[&params[0].id], 2.0);
+            }
+        }
+        let expected = 2.0 + 0.5 * (fast - 2.0);
+        assert!((params[0].data - expected).abs() < 1e-12);
+        assert!((opt.slow[&params[0].id] - expected).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_skips_frozen_params() {
+        // Plain Value::new leaves are still updated; only the frozen marker opts out.
+        let w = Value::new(1.0);
+        let mut frozen = Value::new_param(1.0);
+        frozen.frozen = true;
+        let loss = &w * 2.0 + &frozen * 3.0;
+        let grads = loss.backward();
+
+        let updated = sgd_step(&[w.clone(), frozen.clone()], &grads, 0.1);
+        assert_eq!((updated[0].data, updated[1].data), (0.8, 1.0));
+        assert!(updated[1].frozen && updated[1].trainable);
+
+        let mut params = [w, frozen];
+        SGD::new(0.1).step(&mut params, &grads);
+        Adadelta::default().step(&mut params, &grads);
+        assert_eq!(params[1].data, 1.0);
+        assert!(params[0].data < 0.8);
+    }
+
+    #[test]
+    #[should_panic(expected = "max_step must be a non-negative number")]
+    fn test_sgd_negative_max_step() {
+        let _ = SGD::new(0.1).with_max_step(-0.1);
+    }
+
+    #[test]
+    #[should_panic(expected = "max_step must be a non-negative number")]
+    fn test_sgd_nan_max_step() {
+        let _ = SGD::new(0.1).with_max_step(f64::NAN);
+    }
+
+    #[test]
+    #[should_panic(expected = "LookAhead needs k > 0")]
+    fn test_lookahead_zero_k() {
+        let _ = LookAhead::new(SGD::new(0.1), 0, 0.5);
+    }
+}
@@ -0,0 +1,84 @@
+use crate::nn::MLP;
+use crate::Value;
+
+// For each input, returns (analytic, numeric) where analytic comes from backward() and
+// numeric is the central difference (f(x + eps) - f(x - eps)) / 2eps.
+pub fn audit_gradients(build: impl Fn(&[Value]) -> Value, inputs: &[f64], eps: f64) -> Vec<(f64, f64)> {
+    let values = inputs.iter().map(|&x| Value::new(x)).collect::<Vec<_>>();
+    let grads = build(&values).backward();
+    let eval = |i: usize, delta: f64| {
+        let shifted = inputs.iter().enumerate()
+            .map(|(j, &x)| Value::new(if i == j { x + delta } else { x }))
+            .collect::<Vec<_>>();
+        build(&shifted).data
+    };
+    values
+        .iter()
+        .enumerate()
+        .map(|(i, v)| {
+            let analytic = grads.0.get(&v.id).copied().unwrap_or(0.0);
+            let numeric = (eval(i, eps) - eval(i, -eps)) / (2.0 * eps);
+            (analytic, numeric)
+        })
+        .collect()
+}
+
+// Largest |analytic - numeric| over all parameters, using the sum of the outputs as loss and
+// central differences obtained by perturbing one parameter's data at a time.
+pub fn grad_check_mlp(mlp: &MLP, input: &[f64], eps: f64) -> f64 {
+    let loss = |mlp: &MLP| {
+        let x = input.iter().map(|&x| Value::new(x)).collect::<Vec<_>>();
+        mlp.forward(&x).iter().fold(Value::default(), |acc, o| acc + o)
+    };
+    let params = mlp.parameters();
+    let grads = loss(mlp).backward();
+    let eval = |i: usize, delta: f64| {
+        let mut shifted = params.clone();
+        shifted[i].data += delta;
+        let mut perturbed = mlp.clone();
+        perturbed.update_parameters(shifted);
+        loss(&perturbed).data
+    };
+    params
+        .iter()
+        .enumerate()
+        .map(|(i, p)| {
+            let analytic = grads.0.get(&p.id).copied().unwrap_or(0.0);
+            let numeric = (eval(i, eps) - eval(i, -eps)) / (2.0 * eps);
+            (analytic - numeric).abs()
+        })
+        .fold(0.0, f64::max)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::nn::MLP;
+    use crate::testing::{audit_gradients, grad_check_mlp};
+    use crate::Value;
+
+    fn check(name: &str, build: impl Fn(&[Value]) -> Value, inputs: &[f64]) {
+        for (analytic, numeric) in audit_gradients(build, inputs, 1e-6) {
+            assert!((analytic - numeric).abs() < 1e-6, "{}: analytic {} vs numeric {}", name, analytic, numeric);
+        }
+    }
+
+    #[test]
+    fn test_audit_gradients() {
+        check("tanh", |x| x[0].tanh(), &[0.3]);
+        check("exp", |x| x[0].exp(), &[1.2]);
+        check("ln", |x| x[0].ln(), &[2.5]);
+        check("pow", |x| x[0].pow(&Value::from(3.0)), &[1.7]);
+        check("pow exponent", |x| x[0].pow(&x[1]), &[1.7, 2.3]);
+        check("relu", |x| x[0].relu() + x[1].relu(), &[0.8, -0.4]);
+        check("composite", |x| (&x[0] * &x[1]).tanh().exp() + x[1].ln(), &[0.5, 1.5]);
+        check("div", |x| &x[0] / &x[1], &[6.0, 3.0]);
+        check("leaf times op", |x| &x[0] * (&x[0] + 1.0), &[2.0]);
+        check("leaf times tanh", |x| &x[0] * x[0].tanh(), &[0.7]);
+    }
+
+    #[test]
+    fn test_grad_check_mlp() {
+        let mlp = MLP::new(3, &[4, 4, 1]);
+        assert!(grad_check_mlp(&mlp, &[2.0, 3.0, -1.0], 1e-6) < 1e-4);
+    }
+}